@@ -37,7 +37,7 @@ macro_rules! println {
 }
 
 #[repr(C, packed)]
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub struct TrapFrame {
     pub ra: u32,
     pub gp: u32,
@@ -117,8 +117,126 @@ pub const SYS_GETCHAR: u32 = 2;
 pub const SYS_EXIT: u32 = 3;
 pub const SYS_READFILE: u32 = 4;
 pub const SYS_WRITEFILE: u32 = 5;
+pub const SYS_SOCKET: u32 = 6;
+pub const SYS_BIND: u32 = 7;
+pub const SYS_SENDTO: u32 = 8;
+pub const SYS_RECVFROM: u32 = 9;
+pub const SYS_CLOSE: u32 = 10;
+pub const SYS_LISTEN: u32 = 11;
+pub const SYS_ACCEPT: u32 = 12;
+pub const SYS_CONNECT: u32 = 13;
+pub const SYS_SEND: u32 = 14;
+pub const SYS_RECV: u32 = 15;
+pub const SYS_GETRANDOM: u32 = 16;
+pub const SYS_READKEY: u32 = 17;
+pub const SYS_TCGETATTR: u32 = 18;
+pub const SYS_TCSETATTR: u32 = 19;
+pub const SYS_KILL: u32 = 20;
+pub const SYS_SIGACTION: u32 = 21;
+pub const SYS_SIGRETURN: u32 = 22;
+pub const SYS_GETPID: u32 = 23;
+pub const SYS_SLEEP: u32 = 24;
+pub const SYS_CLOCK_GETTIME: u32 = 25;
+pub const SYS_SHUTDOWN: u32 = 26;
+pub const SYS_REBOOT: u32 = 27;
 
-pub const VIRTIO_BLK_PADDR: usize = 0x10001000;
+pub const SOCK_STREAM: u32 = 1;
+pub const SOCK_DGRAM: u32 = 2;
+
+pub const SIGINT: u32 = 2;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGUSR2: u32 = 12;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const NSIG: usize = 32;
+
+// sigaction に渡すハンドラの特別な値
+pub const SIG_DFL: u32 = 0;
+pub const SIG_IGN: u32 = 1;
+
+// getchar がこれを返したら入力の終わり (Ctrl-D)
+pub const EOF: u32 = 0xffff_ffff;
+// 入力を待っている間にシグナルが届いた
+pub const EINTR: u32 = 0xffff_fffe;
+
+// コンソールの TTY の設定
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub lflag: u32,
+    pub cc: [u8; NCCS],
+}
+
+// iflag: 入力の CR を LF に変える
+pub const ICRNL: u32 = 1 << 0;
+// oflag: 出力の LF を CR LF に変える
+pub const ONLCR: u32 = 1 << 0;
+// lflag: 行単位で入力を編集する、入力を表示する、Ctrl-C などを特別扱いする
+pub const ICANON: u32 = 1 << 0;
+pub const ECHO: u32 = 1 << 1;
+pub const ISIG: u32 = 1 << 2;
+
+// cc の添字
+pub const VINTR: usize = 0;
+pub const VERASE: usize = 1;
+pub const VKILL: usize = 2;
+pub const VEOF: usize = 3;
+pub const NCCS: usize = 4;
+
+// readkey で読み出すキーボードのイベント。`code` は Linux の evdev のキーコード
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct KeyEvent {
+    pub code: u16,
+    pub value: u8,
+    pub modifiers: u8,
+    // キーマップで変換した文字。文字にならないキーは 0
+    pub ch: u8,
+}
+
+pub const KEY_RELEASED: u8 = 0;
+pub const KEY_PRESSED: u8 = 1;
+pub const KEY_REPEATED: u8 = 2;
+
+pub const KEY_MOD_SHIFT: u8 = 1 << 0;
+pub const KEY_MOD_CTRL: u8 = 1 << 1;
+pub const KEY_MOD_ALT: u8 = 1 << 2;
+pub const KEY_MOD_CAPSLOCK: u8 = 1 << 3;
+
+// readkey にこのフラグを渡すと、イベントがなくても待たずに戻る
+pub const READKEY_NONBLOCK: u32 = 1;
+
+// 1970-01-01 (UTC) からの時刻。RTC がなければ起動した時刻を起点にする
+pub const CLOCK_REALTIME: u32 = 0;
+// 起動してからの時間。時計を合わせても戻らない
+pub const CLOCK_MONOTONIC: u32 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Timespec {
+    pub sec: u64,
+    pub nsec: u32,
+}
+
+// ポート番号はホストのバイトオーダーで持つ
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SockAddr {
+    pub addr: [u8; 4],
+    pub port: u16,
+}
+
+// sendto / recvfrom はシステムコールの引数が足りないので、この構造体のアドレスを渡す
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SockMsg {
+    pub buf: u32,
+    pub len: u32,
+    pub addr: SockAddr,
+}
 
 pub fn ascii_len(buf: *const u8) -> usize {
     let len;
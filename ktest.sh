@@ -0,0 +1,33 @@
+#!/bin/bash
+# カーネルのテストを QEMU で実行する。結果は終了コードで分かる (成功なら 0)。
+# cargo test の runner としても呼ばれ、そのときはテスト用のカーネルを引数に受け取る
+set -ue
+
+QEMU=qemu-system-riscv32
+USER=user/target/riscv32i-unknown-none-elf/release/user
+
+if [ $# -gt 0 ]; then
+    # カーネルは sifive_test で電源を切るので、QEMU の終了コードがそのまま結果になる。
+    # 止まってしまったときのために時間を区切る
+    exec timeout 120 $QEMU -machine virt -bios default -nographic --no-reboot \
+        -global virtio-mmio.force-legacy=false \
+        -drive id=drive0,file=ktest.tar,format=raw \
+        -device virtio-blk-device,drive=drive0,bus=virtio-mmio-bus.0 \
+        -netdev user,id=net0 \
+        -device virtio-net-device,netdev=net0,bus=virtio-mmio-bus.1 \
+        -kernel "$1"
+fi
+
+set -x
+
+# ルートにマウントするディスク。テストで最後のセクタを書き換えるので毎回作り直す
+mkdir -p ktest
+echo "hello from ktest" > ktest/hello.txt
+rm -f ktest.tar
+(cd ktest && tar cf ../ktest.tar --format=ustar ./*)
+
+(cd user && cargo build --release)
+llvm-objcopy --set-section-flags .bss=alloc,contents -O binary $USER shell.bin
+llvm-objcopy -Ibinary -Oelf32-littleriscv shell.bin shell.bin.o
+
+cargo test --release
@@ -10,14 +10,40 @@ echo "Lorem ipsum dolor sit amet, consectetur adipiscing elit. In ut magna conse
 echo "hello world!!" > disk/hello.txt
 (cd disk && tar cf ../disk.tar --format=ustar ./*)
 
+mkdir -p tools
+echo "this disk is mounted read-only at /tools/" > tools/readme.txt
+(cd tools && tar cf ../tools.tar --format=ustar ./*)
+
+# shared/ は virtio-9p でゲストの /host/ から読み書きできる
+mkdir -p shared
+[ -f shared/hello.txt ] || echo "hello from the host" > shared/hello.txt
+
 (cd user && cargo build --release)
 llvm-objcopy --set-section-flags .bss=alloc,contents -O binary $USER shell.bin
 llvm-objcopy -Ibinary -Oelf32-littleriscv shell.bin shell.bin.o
 
 cargo build --release
 
-$QEMU -machine virt -bios default -nographic -serial mon:stdio --no-reboot \
+# 標準入出力は UART (SBI) と virtio-console で共有し、ログは kernel.log に書き出す。
+# virtio-keyboard にはモニタ (Ctrl-A c) の sendkey でキーイベントを送れる
+$QEMU -machine virt -smp 4 -bios default -nographic --no-reboot \
+    -chardev stdio,id=char0,mux=on \
+    -serial chardev:char0 -mon chardev=char0 \
     -d unimp,guest_errors,int,cpu_reset -D qemu.log \
+    -global virtio-mmio.force-legacy=false \
     -drive id=drive0,file=disk.tar,format=raw \
     -device virtio-blk-device,drive=drive0,bus=virtio-mmio-bus.0 \
+    -drive id=drive1,file=tools.tar,format=raw,readonly=on \
+    -device virtio-blk-device,drive=drive1,bus=virtio-mmio-bus.1 \
+    -device virtio-serial-device,bus=virtio-mmio-bus.2 \
+    -device virtconsole,chardev=char0 \
+    -chardev file,id=log0,path=kernel.log \
+    -device virtserialport,chardev=log0,name=log,nr=1 \
+    -netdev user,id=net0,hostfwd=udp::7777-:7777,hostfwd=tcp::2323-:2323 \
+    -device virtio-net-device,netdev=net0,bus=virtio-mmio-bus.3 \
+    -object rng-random,id=rng0,filename=/dev/urandom \
+    -device virtio-rng-device,rng=rng0,bus=virtio-mmio-bus.4 \
+    -fsdev local,id=fs0,path=shared,security_model=none \
+    -device virtio-9p-device,fsdev=fs0,mount_tag=host0,bus=virtio-mmio-bus.5 \
+    -device virtio-keyboard-device,bus=virtio-mmio-bus.6 \
     -kernel $KERNEL
@@ -0,0 +1,310 @@
+use core::ptr;
+
+use crate::{
+    block::{block_device, BlockError, SECTOR_SIZE},
+    process::wait_event,
+    PM,
+};
+
+const BUF_NUM: usize = 32;
+const PREFETCH_MAX: usize = BUF_NUM / 2;
+
+#[derive(Copy, Clone)]
+pub struct Buf {
+    valid: bool,
+    dirty: bool,
+    // デバイスとの転送中。転送が終わるまで他のプロセスは中身を使えない
+    busy: bool,
+    refcnt: u32,
+    dev: usize,
+    sector: u64,
+    last_used: u64,
+    pub data: [u8; SECTOR_SIZE],
+}
+
+impl Buf {
+    const fn new() -> Self {
+        Self {
+            valid: false,
+            dirty: false,
+            busy: false,
+            refcnt: 0,
+            dev: 0,
+            sector: 0,
+            last_used: 0,
+            data: [0; SECTOR_SIZE],
+        }
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+}
+
+pub struct BufferCache {
+    bufs: [Buf; BUF_NUM],
+    tick: u64,
+}
+
+impl BufferCache {
+    pub const fn new() -> Self {
+        Self {
+            bufs: [Buf::new(); BUF_NUM],
+            tick: 0,
+        }
+    }
+
+    // セクタのバッファを返す。キャッシュになければデバイスから読み込む
+    pub fn read(&mut self, dev: usize, sector: u64) -> Result<&mut Buf, BlockError> {
+        let i = self.acquire(dev, sector)?;
+        if !self.bufs[i].valid {
+            // 読み込みで眠っている間に、同じセクタを acquire したプロセスは busy で待たせる
+            self.bufs[i].busy = true;
+            let result = block_device(dev).read(sector, &mut self.bufs[i].data);
+            self.bufs[i].valid = result.is_ok();
+            self.unbusy(i);
+            if let Err(err) = result {
+                self.bufs[i].refcnt -= 1;
+                return Err(err);
+            }
+        }
+        Ok(&mut self.bufs[i])
+    }
+
+    // 呼び出し側がセクタ全体を上書きするときに使う。デバイスから読まずに 0 で埋めたバッファを返す
+    pub fn get(&mut self, dev: usize, sector: u64) -> Result<&mut Buf, BlockError> {
+        let i = self.acquire(dev, sector)?;
+        let buf = &mut self.bufs[i];
+        if !buf.valid {
+            buf.data.fill(0);
+            buf.valid = true;
+        }
+        Ok(buf)
+    }
+
+    // `sector` から `count` セクタをキャッシュに読み込んでおく。
+    // キャッシュにないセクタが続くところは、まとめて 1 回のリクエストで読む
+    pub fn prefetch(&mut self, dev: usize, sector: u64, count: u64) -> Result<(), BlockError> {
+        let mut run = [0; PREFETCH_MAX];
+        let mut run_start = sector;
+        let mut n = 0;
+        for s in sector..(sector + count) {
+            let i = match self.acquire(dev, s) {
+                Ok(i) => i,
+                Err(err) => {
+                    // まだ読んでいないバッファの参照を返しておかないと、二度と追い出せなくなる。
+                    // read_run は失敗しても自分で参照を返す
+                    self.release_run(&run[0..n]);
+                    return Err(err);
+                }
+            };
+            // acquire は眠ることがあるので、キャッシュにあるかどうかは参照を取ってから確かめる
+            if self.bufs[i].valid {
+                self.bufs[i].refcnt -= 1;
+                self.read_run(dev, run_start, &run[0..n])?;
+                n = 0;
+                continue;
+            }
+
+            // 読み込みが終わるまで、同じセクタを acquire したプロセスを待たせる
+            self.bufs[i].busy = true;
+            if n == 0 {
+                run_start = s;
+            }
+            run[n] = i;
+            n += 1;
+            if n == run.len() {
+                self.read_run(dev, run_start, &run[0..n])?;
+                n = 0;
+            }
+        }
+        self.read_run(dev, run_start, &run[0..n])
+    }
+
+    pub fn release(&mut self, dev: usize, sector: u64) {
+        match self.lookup(dev, sector) {
+            Some(i) if self.bufs[i].refcnt > 0 => self.bufs[i].refcnt -= 1,
+            _ => panic!("bcache: release of unreferenced dev={dev} sector={sector}"),
+        }
+    }
+
+    // `dev` の書き換えられたバッファをすべて書き戻す。連続したセクタは 1 回のリクエストにまとめる
+    pub fn flush(&mut self, dev: usize) -> Result<(), BlockError> {
+        // 他のプロセスが転送している最中のバッファがあれば、終わるのを待つ
+        while let Some(i) = (0..BUF_NUM).find(|&i| self.bufs[i].busy && self.bufs[i].dev == dev) {
+            self.wait_unbusy(i);
+        }
+
+        let mut dirty = [0; BUF_NUM];
+        let mut n = 0;
+        for (i, buf) in self.bufs.iter_mut().enumerate() {
+            if buf.valid && buf.dirty && buf.dev == dev {
+                // 書き込み中に追い出されないようにする
+                buf.busy = true;
+                dirty[n] = i;
+                n += 1;
+            }
+        }
+        dirty[0..n].sort_unstable_by_key(|&i| self.bufs[i].sector);
+
+        let mut start = 0;
+        while start < n {
+            let mut end = start + 1;
+            while end < n && self.bufs[dirty[end]].sector == self.bufs[dirty[end - 1]].sector + 1 {
+                end += 1;
+            }
+
+            let mut segs: [&[u8]; BUF_NUM] = [&[]; BUF_NUM];
+            for (seg, &i) in segs.iter_mut().zip(dirty[start..end].iter()) {
+                *seg = &self.bufs[i].data;
+            }
+            let result = block_device(dev)
+                .write_vectored(self.bufs[dirty[start]].sector, &segs[0..(end - start)]);
+            if result.is_ok() {
+                for &i in dirty[start..end].iter() {
+                    self.bufs[i].dirty = false;
+                }
+            }
+            start = end;
+            if result.is_err() {
+                for &i in dirty[0..n].iter() {
+                    self.unbusy(i);
+                }
+                return result;
+            }
+        }
+        for &i in dirty[0..n].iter() {
+            self.unbusy(i);
+        }
+
+        block_device(dev).flush()
+    }
+
+    // discard したあとなど、デバイスが中身を保持しなくなったセクタをキャッシュから捨てる
+    pub fn invalidate(&mut self, dev: usize, sector: u64, count: u64) {
+        for buf in self.bufs.iter_mut() {
+            if buf.refcnt == 0
+                && !buf.busy
+                && buf.dev == dev
+                && buf.sector >= sector
+                && buf.sector < sector + count
+            {
+                buf.valid = false;
+                buf.dirty = false;
+            }
+        }
+    }
+
+    fn read_run(&mut self, dev: usize, sector: u64, run: &[usize]) -> Result<(), BlockError> {
+        if run.is_empty() {
+            return Ok(());
+        }
+
+        let mut segs: [&mut [u8]; PREFETCH_MAX] = Default::default();
+        for (seg, &i) in segs.iter_mut().zip(run.iter()) {
+            // run には重複しないバッファのインデックスしか入らない
+            *seg = unsafe {
+                core::slice::from_raw_parts_mut(self.bufs[i].data.as_mut_ptr(), SECTOR_SIZE)
+            };
+        }
+        let result = block_device(dev).read_vectored(sector, &mut segs[0..run.len()]);
+
+        for &i in run.iter() {
+            self.bufs[i].valid = result.is_ok();
+        }
+        self.release_run(run);
+        result
+    }
+
+    fn release_run(&mut self, run: &[usize]) {
+        for &i in run.iter() {
+            self.bufs[i].refcnt -= 1;
+            self.unbusy(i);
+        }
+    }
+
+    fn buf_chan(&self, i: usize) -> usize {
+        ptr::addr_of!(self.bufs[i]) as usize
+    }
+
+    fn wait_unbusy(&self, i: usize) {
+        let busy = ptr::addr_of!(self.bufs[i].busy);
+        wait_event(self.buf_chan(i), || unsafe { !ptr::read_volatile(busy) });
+    }
+
+    fn unbusy(&mut self, i: usize) {
+        self.bufs[i].busy = false;
+        unsafe { PM.wakeup(self.buf_chan(i)) };
+    }
+
+    fn lookup(&self, dev: usize, sector: u64) -> Option<usize> {
+        self.bufs
+            .iter()
+            .position(|b| (b.valid || b.refcnt > 0) && b.dev == dev && b.sector == sector)
+    }
+
+    // セクタのバッファの参照を取る。デバイスとの転送中なら終わるまで眠る。
+    // 眠っている間にキャッシュの中身が変わるので、起きたら探し直す
+    fn acquire(&mut self, dev: usize, sector: u64) -> Result<usize, BlockError> {
+        let i = loop {
+            if let Some(i) = self.lookup(dev, sector) {
+                if self.bufs[i].busy {
+                    self.wait_unbusy(i);
+                    continue;
+                }
+                break i;
+            }
+
+            let Some(i) = self.evict()? else {
+                continue;
+            };
+            let buf = &mut self.bufs[i];
+            buf.valid = false;
+            buf.dirty = false;
+            buf.dev = dev;
+            buf.sector = sector;
+            break i;
+        };
+
+        self.tick += 1;
+        let buf = &mut self.bufs[i];
+        buf.refcnt += 1;
+        buf.last_used = self.tick;
+        Ok(i)
+    }
+
+    // 参照されていないバッファのうち、もっとも長く使われていないものを選ぶ。
+    // すべて参照されていれば NoBuffers を返す。書き戻していないデータがあれば書き戻すが、
+    // 書き込みで眠っている間にキャッシュの中身が変わるので、そのときは None を返して選び直させる
+    fn evict(&mut self) -> Result<Option<usize>, BlockError> {
+        let victim = self
+            .bufs
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.refcnt == 0 && !b.busy)
+            .min_by_key(|(_, b)| if b.valid { b.last_used } else { 0 });
+        let Some((i, _)) = victim else {
+            // 書き戻し中のバッファしか空いていなければ、終わるのを待つ
+            let i = self
+                .bufs
+                .iter()
+                .position(|b| b.refcnt == 0)
+                .ok_or(BlockError::NoBuffers)?;
+            self.wait_unbusy(i);
+            return Ok(None);
+        };
+
+        let buf = &mut self.bufs[i];
+        if !(buf.valid && buf.dirty) {
+            return Ok(Some(i));
+        }
+
+        buf.busy = true;
+        let result = block_device(buf.dev).write(buf.sector, &buf.data);
+        if result.is_ok() {
+            self.bufs[i].dirty = false;
+        }
+        self.unbusy(i);
+        result.map(|()| None)
+    }
+}
@@ -0,0 +1,167 @@
+#[cfg(test)]
+use common::{align_up, PAGE_SIZE};
+
+#[cfg(test)]
+use crate::memory::alloc_pages;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BlockError {
+    OutOfRange,
+    InvalidLength,
+    ReadOnly,
+    Unsupported,
+    NoBuffers,
+    Io(u8),
+}
+
+// 転送は常にセクタ単位。`buf.len()` は SECTOR_SIZE の倍数で、
+// `sector` から始まる範囲は capacity() に収まっていなければならない
+pub trait BlockDevice {
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
+    fn capacity(&self) -> u64;
+    fn flush(&mut self) -> Result<(), BlockError>;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    // `sector` から `count` セクタのデータがもう要らないことをデバイスに伝える。
+    // そのあとの中身は不定になる
+    fn discard(&mut self, _sector: u64, _count: u64) -> Result<(), BlockError> {
+        Err(BlockError::Unsupported)
+    }
+
+    fn write_zeroes(&mut self, _sector: u64, _count: u64) -> Result<(), BlockError> {
+        Err(BlockError::Unsupported)
+    }
+
+    // スキャッタギャザー版。`bufs` を `sector` から順に隙間なく転送する。
+    // それぞれのバッファはセクタ単位でなければならない
+    fn read_vectored(&mut self, sector: u64, bufs: &mut [&mut [u8]]) -> Result<(), BlockError> {
+        let mut sector = sector;
+        for buf in bufs.iter_mut() {
+            self.read(sector, buf)?;
+            sector += (buf.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    fn write_vectored(&mut self, sector: u64, bufs: &[&[u8]]) -> Result<(), BlockError> {
+        let mut sector = sector;
+        for buf in bufs.iter() {
+            self.write(sector, buf)?;
+            sector += (buf.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> Result<(), BlockError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(BlockError::InvalidLength);
+        }
+        match sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.capacity() => {}
+            _ => return Err(BlockError::OutOfRange),
+        }
+        Ok(())
+    }
+}
+
+// メモリ上のディスク。いまはカーネルのテストでだけ使う
+#[cfg(test)]
+pub struct RamDisk {
+    data: *mut u8,
+    sectors: u64,
+}
+
+#[cfg(test)]
+impl RamDisk {
+    pub fn new(sectors: u64) -> Self {
+        let size = align_up(sectors as usize * SECTOR_SIZE, PAGE_SIZE);
+        let data = alloc_pages(size / PAGE_SIZE) as *mut u8;
+        Self { data, sectors }
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for RamDisk {
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(sector, buf.len())?;
+        unsafe {
+            let src = self.data.add(sector as usize * SECTOR_SIZE);
+            core::ptr::copy(src, buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check_range(sector, buf.len())?;
+        unsafe {
+            let dst = self.data.add(sector as usize * SECTOR_SIZE);
+            core::ptr::copy(buf.as_ptr(), dst, buf.len());
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.sectors
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn discard(&mut self, sector: u64, count: u64) -> Result<(), BlockError> {
+        self.write_zeroes(sector, count)
+    }
+
+    fn write_zeroes(&mut self, sector: u64, count: u64) -> Result<(), BlockError> {
+        // 掛け算があふれるほど大きな `count` は範囲外として扱う
+        let len = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(SECTOR_SIZE))
+            .ok_or(BlockError::OutOfRange)?;
+        self.check_range(sector, len)?;
+        unsafe {
+            let dst = self.data.add(sector as usize * SECTOR_SIZE);
+            core::ptr::write_bytes(dst, 0, len);
+        }
+        Ok(())
+    }
+}
+
+const BLOCK_DEVICES_MAX: usize = 8;
+
+static mut BLOCK_DEVICES: [Option<(&'static str, *mut dyn BlockDevice)>; BLOCK_DEVICES_MAX] =
+    [None; BLOCK_DEVICES_MAX];
+
+// `dev` を `name` ("vda" など) という名前で登録し、デバイス番号を返す
+pub fn register_block_device(name: &'static str, dev: &'static mut dyn BlockDevice) -> usize {
+    unsafe {
+        match BLOCK_DEVICES.iter().position(|d| d.is_none()) {
+            Some(id) => {
+                BLOCK_DEVICES[id] = Some((name, dev));
+                id
+            }
+            None => panic!("block: too many devices"),
+        }
+    }
+}
+
+pub fn find_block_device(name: &str) -> Option<usize> {
+    unsafe {
+        BLOCK_DEVICES
+            .iter()
+            .position(|d| matches!(d, Some((n, _)) if *n == name))
+    }
+}
+
+pub fn block_device(id: usize) -> &'static mut dyn BlockDevice {
+    match unsafe { BLOCK_DEVICES[id] } {
+        Some((_, dev)) => unsafe { &mut *dev },
+        None => panic!("block: no such device {id}"),
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{
+    sbi::{sbi_console_write, sbi_getchar},
+    uart::uart,
+    virtio_console::virtio_console,
+};
+
+const CONSOLE_BUF_SIZE: usize = 128;
+
+// virtio-console と SBI へは 1 行ずつまとめて送る
+static mut CONSOLE_BUF: [u8; CONSOLE_BUF_SIZE] = [0; CONSOLE_BUF_SIZE];
+static mut CONSOLE_BUF_LEN: usize = 0;
+
+// common の println! から呼ばれる。virtio-console がなければ UART に 1 文字ずつ出力する。
+// どちらもなければ SBI に出力する
+#[no_mangle]
+pub fn putchar(ch: u8) {
+    if virtio_console().is_none() {
+        if let Some(uart) = uart() {
+            // UART が見つかる前に SBI へ出そうとしていた分を先に出す
+            console_flush();
+            uart.putchar(ch);
+            return;
+        }
+    }
+
+    unsafe {
+        CONSOLE_BUF[CONSOLE_BUF_LEN] = ch;
+        CONSOLE_BUF_LEN += 1;
+        if ch == b'\n' || CONSOLE_BUF_LEN == CONSOLE_BUF_SIZE {
+            console_flush();
+        }
+    }
+}
+
+pub fn console_flush() {
+    unsafe {
+        if CONSOLE_BUF_LEN == 0 {
+            return;
+        }
+        let len = CONSOLE_BUF_LEN;
+        CONSOLE_BUF_LEN = 0;
+        match virtio_console() {
+            Some(console) => console.write(&CONSOLE_BUF[0..len]),
+            None => sbi_console_write(&CONSOLE_BUF[0..len]),
+        }
+    }
+}
+
+// 入力がなければ -1 を返す。virtio-console と UART の両方があればどちらからも読む
+pub fn getchar() -> i32 {
+    console_flush();
+    if let Some(ch) = virtio_console().and_then(|console| console.getchar()) {
+        return ch as i32;
+    }
+    match uart() {
+        Some(uart) => uart.getchar().map(|ch| ch as i32).unwrap_or(-1),
+        None if virtio_console().is_none() => sbi_getchar(),
+        None => -1,
+    }
+}
+
+// 入力が割り込みで届くなら true。SBI のコンソールはポーリングするしかない
+pub fn console_can_wait() -> bool {
+    virtio_console().is_some() || uart().is_some()
+}
+
+struct Log;
+
+impl core::fmt::Write for Log {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if !virtio_console().is_some_and(|console| console.write_log(s.as_bytes())) {
+            common::print!("{}", s);
+        }
+        Ok(())
+    }
+}
+
+pub fn _log(args: core::fmt::Arguments) {
+    core::fmt::Write::write_fmt(&mut Log, args).unwrap();
+}
+
+// virtio-console のログ用ポートがあればそちらに、なければコンソールに出力する
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::console::_log(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
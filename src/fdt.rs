@@ -0,0 +1,274 @@
+use common::{ascii_len, println, PAGE_SIZE};
+use core::ptr;
+
+use crate::virtio::VIRTIO_MMIO_COUNT;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+const FDT_DEPTH_MAX: usize = 16;
+// DTB に timebase-frequency がないときは QEMU virt の値を使う
+const TIMEBASE_FREQ_DEFAULT: u32 = 10_000_000;
+
+#[derive(Copy, Clone, Debug)]
+pub struct MmioRegion {
+    pub base: usize,
+    pub size: usize,
+    pub irq: u32,
+}
+
+impl MmioRegion {
+    const fn new() -> Self {
+        Self {
+            base: 0,
+            size: 0,
+            irq: 0,
+        }
+    }
+}
+
+// デバイスツリーから見つけたマシンの構成
+#[derive(Copy, Clone, Debug)]
+pub struct Platform {
+    pub dtb: usize,
+    pub dtb_size: usize,
+    pub ram_base: usize,
+    pub ram_size: usize,
+    pub timebase_freq: u32,
+    pub hart_count: usize,
+    pub plic: MmioRegion,
+    pub uart: Option<MmioRegion>,
+    pub rtc: Option<MmioRegion>,
+    // 書き込むと QEMU が終了する sifive_test デバイス
+    pub test: Option<MmioRegion>,
+    // アドレスの小さい順 (QEMU の virtio-mmio-bus.0, 1, ... の順) に並ぶ
+    pub virtio: [MmioRegion; VIRTIO_MMIO_COUNT],
+    pub virtio_count: usize,
+}
+
+impl Platform {
+    pub const fn new() -> Self {
+        Self {
+            dtb: 0,
+            dtb_size: 0,
+            ram_base: 0,
+            ram_size: 0,
+            timebase_freq: 0,
+            hart_count: 0,
+            plic: MmioRegion::new(),
+            uart: None,
+            rtc: None,
+            test: None,
+            virtio: [MmioRegion::new(); VIRTIO_MMIO_COUNT],
+            virtio_count: 0,
+        }
+    }
+
+    pub fn ram_end(&self) -> usize {
+        self.ram_base + self.ram_size
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum NodeKind {
+    Other,
+    Memory,
+    Cpu,
+    Plic,
+    Uart,
+    Rtc,
+    Test,
+    VirtioMmio,
+}
+
+#[derive(Copy, Clone)]
+struct Node {
+    kind: NodeKind,
+    // 子ノードの reg の解釈に使う
+    address_cells: u32,
+    size_cells: u32,
+    reg: Option<(usize, usize)>,
+    irq: u32,
+}
+
+impl Node {
+    const fn new() -> Self {
+        // 仕様上のデフォルト値
+        Self {
+            kind: NodeKind::Other,
+            address_cells: 2,
+            size_cells: 1,
+            reg: None,
+            irq: 0,
+        }
+    }
+}
+
+struct Fdt {
+    base: *const u8,
+    off_struct: usize,
+    off_strings: usize,
+}
+
+impl Fdt {
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { u32::from_be(ptr::read_unaligned(self.base.add(offset) as *const u32)) }
+    }
+
+    fn cstr(&self, offset: usize) -> &'static str {
+        unsafe {
+            let s = self.base.add(offset);
+            core::str::from_utf8(core::slice::from_raw_parts(s, ascii_len(s) - 1)).unwrap_or("")
+        }
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.base.add(offset), len) }
+    }
+}
+
+// `cells` 個の 32 ビットセルを 1 つの値として読み出し、残りを返す
+fn read_cells(data: &[u8], cells: u32) -> (u64, &[u8]) {
+    let len = core::cmp::min(cells as usize * 4, data.len());
+    let value = data[0..len].chunks(4).fold(0u64, |v, c| {
+        (v << 32) | u32::from_be_bytes(c.try_into().unwrap()) as u64
+    });
+    (value, &data[len..])
+}
+
+// stringlist 形式のプロパティ (compatible など) に `s` が含まれるか
+fn has_string(data: &[u8], s: &str) -> bool {
+    data.split(|&c| c == 0).any(|entry| entry == s.as_bytes())
+}
+
+fn compatible_kind(data: &[u8]) -> NodeKind {
+    if has_string(data, "virtio,mmio") {
+        NodeKind::VirtioMmio
+    } else if has_string(data, "riscv,plic0") || has_string(data, "sifive,plic-1.0.0") {
+        NodeKind::Plic
+    } else if has_string(data, "ns16550a") {
+        NodeKind::Uart
+    } else if has_string(data, "google,goldfish-rtc") {
+        NodeKind::Rtc
+    } else if has_string(data, "sifive,test0") {
+        NodeKind::Test
+    } else {
+        NodeKind::Other
+    }
+}
+
+// OpenSBI が a1 で渡してくる Flattened Device Tree を読む
+pub unsafe fn fdt_parse(dtb: *const u8) -> Platform {
+    let header = Fdt {
+        base: dtb,
+        off_struct: 0,
+        off_strings: 0,
+    };
+    if dtb.is_null() || header.read32(0) != FDT_MAGIC {
+        panic!("fdt: invalid device tree at {:p}", dtb);
+    }
+    let fdt = Fdt {
+        base: dtb,
+        off_struct: header.read32(8) as usize,
+        off_strings: header.read32(12) as usize,
+    };
+
+    let mut platform = Platform::new();
+    platform.dtb = dtb as usize;
+    platform.dtb_size = header.read32(4) as usize;
+
+    let mut stack = [Node::new(); FDT_DEPTH_MAX];
+    let mut depth = 0;
+    let mut offset = fdt.off_struct;
+    loop {
+        let token = fdt.read32(offset);
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = fdt.cstr(offset);
+                offset += (name.len() + 1 + 3) & !3;
+                depth += 1;
+                if depth >= FDT_DEPTH_MAX {
+                    panic!("fdt: too deep nesting at {name}");
+                }
+                stack[depth] = Node::new();
+            }
+            FDT_END_NODE => {
+                let node = &stack[depth];
+                if let Some((base, size)) = node.reg {
+                    let region = MmioRegion {
+                        base,
+                        size,
+                        irq: node.irq,
+                    };
+                    match node.kind {
+                        NodeKind::Memory if platform.ram_size == 0 => {
+                            // -m 2G などでアドレス空間の終わりまで RAM があると、終端が
+                            // 32 ビットに収まらない。収まるページまでしか使わない
+                            let limit = (usize::MAX - base) & !(PAGE_SIZE - 1);
+                            platform.ram_base = base;
+                            platform.ram_size = size.min(limit);
+                        }
+                        NodeKind::Plic => platform.plic = region,
+                        NodeKind::Uart if platform.uart.is_none() => platform.uart = Some(region),
+                        NodeKind::Rtc if platform.rtc.is_none() => platform.rtc = Some(region),
+                        NodeKind::Test if platform.test.is_none() => platform.test = Some(region),
+                        NodeKind::VirtioMmio if platform.virtio_count < VIRTIO_MMIO_COUNT => {
+                            platform.virtio[platform.virtio_count] = region;
+                            platform.virtio_count += 1;
+                        }
+                        _ => {}
+                    }
+                }
+                if node.kind == NodeKind::Cpu {
+                    platform.hart_count += 1;
+                }
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = fdt.read32(offset) as usize;
+                let name = fdt.cstr(fdt.off_strings + fdt.read32(offset + 4) as usize);
+                let data = fdt.bytes(offset + 8, len);
+                offset += 8 + ((len + 3) & !3);
+
+                let (parent, node) = stack.split_at_mut(depth);
+                let parent = parent.last().copied().unwrap_or(Node::new());
+                let node = &mut node[0];
+                match name {
+                    "#address-cells" => node.address_cells = read_cells(data, 1).0 as u32,
+                    "#size-cells" => node.size_cells = read_cells(data, 1).0 as u32,
+                    "reg" => {
+                        let (base, rest) = read_cells(data, parent.address_cells);
+                        let (size, _) = read_cells(rest, parent.size_cells);
+                        node.reg = Some((base as usize, size as usize));
+                    }
+                    "interrupts" => node.irq = read_cells(data, 1).0 as u32,
+                    "compatible" => {
+                        let kind = compatible_kind(data);
+                        if kind != NodeKind::Other {
+                            node.kind = kind;
+                        }
+                    }
+                    "device_type" if has_string(data, "memory") => node.kind = NodeKind::Memory,
+                    "device_type" if has_string(data, "cpu") => node.kind = NodeKind::Cpu,
+                    "timebase-frequency" => platform.timebase_freq = read_cells(data, 1).0 as u32,
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            _ => panic!("fdt: unexpected token {token:x} at offset {offset:x}"),
+        }
+    }
+
+    platform.virtio[0..platform.virtio_count].sort_unstable_by_key(|r| r.base);
+    // 0 のままだとタイマーの計算で 0 除算になる
+    if platform.timebase_freq == 0 {
+        println!("fdt: no timebase-frequency, assuming {TIMEBASE_FREQ_DEFAULT} Hz");
+        platform.timebase_freq = TIMEBASE_FREQ_DEFAULT;
+    }
+    platform
+}
@@ -1,6 +1,37 @@
 use common::{align_up, ascii_len, oct2int, println};
 
-use crate::virtio::Virtio;
+use crate::{
+    bcache::BufferCache,
+    block::{block_device, BlockError, SECTOR_SIZE},
+    log,
+    p9::{
+        p9_attach, p9_clunk, p9_open, p9_read, p9_walk, p9_write, P9Error, P9_O_RDONLY, P9_O_TRUNC,
+        P9_O_WRONLY,
+    },
+    process::wait_event,
+    PM,
+};
+use core::ptr::{self, read_volatile};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FsError {
+    Block(BlockError),
+    P9(P9Error),
+    NotFound,
+    Interrupted,
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> Self {
+        FsError::Block(err)
+    }
+}
+
+impl From<P9Error> for FsError {
+    fn from(err: P9Error) -> Self {
+        FsError::P9(err)
+    }
+}
 
 #[repr(C, packed)]
 struct TarHeader {
@@ -27,57 +58,87 @@ struct TarHeader {
 #[derive(Copy, Clone)]
 pub struct File {
     pub in_use: bool,
+    pub mount: usize,
     pub name: [u8; 100],
     pub data: [u8; 1024],
     pub size: usize,
+    // 最終更新時刻 (1970-01-01 からの秒)
+    pub mtime: u64,
 }
 
 impl File {
     const fn new() -> Self {
         Self {
             in_use: false,
+            mount: 0,
             name: [0; 100],
             data: [0; 1024],
             size: 0,
+            mtime: 0,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &core::str::from_utf8(&self.name).unwrap()[0..(ascii_len(&self.name as *const u8) - 1)]
+    }
 }
 
 const FILES_MAX: usize = 2;
-const DISK_MAX_SIZE: usize = align_up(
-    core::mem::size_of::<File>() * FILES_MAX,
-    Virtio::SECTOR_SIZE as usize,
-);
-
-static mut FILES: [File; FILES_MAX] = [File::new(); FILES_MAX];
-static mut DISK: [u8; DISK_MAX_SIZE] = [0; DISK_MAX_SIZE];
-static mut VIRTIO: *mut Virtio = core::ptr::null_mut();
-
-pub unsafe fn fs_init(virtio: &mut Virtio) {
-    let mut sector = 0;
-    while sector < core::mem::size_of_val(&DISK) / Virtio::SECTOR_SIZE as usize {
-        // println!(
-        //     "{:?}, {}  {}",
-        //     sector,
-        //     core::mem::size_of_val(&DISK),
-        //     Virtio::SECTOR_SIZE
-        // );
-        virtio.read_write_disk(
-            &mut DISK[(sector * Virtio::SECTOR_SIZE as usize)..],
-            sector as u64,
-            false,
-        );
-        sector += 1;
+const MOUNTS_MAX: usize = 4;
+
+#[derive(Copy, Clone, PartialEq)]
+enum MountKind {
+    // ブロックデバイス上の tar アーカイブ
+    Tar,
+    // virtio-9p で共有されたホストのディレクトリ。files は開いたファイルのキャッシュとして使う
+    P9,
+}
+
+// tar アーカイブを 1 つ展開したもの。`path` で始まるファイル名はこのマウントを探す
+#[derive(Copy, Clone)]
+struct Mount {
+    in_use: bool,
+    kind: MountKind,
+    path: &'static str,
+    dev: usize,
+    files: [File; FILES_MAX],
+    // 9p で files[0] を使っている間は立てる。fs_release で下ろす
+    busy: bool,
+    // アーカイブの終端 (終端ブロックを含む) の次のセクタ
+    end_sector: u64,
+}
+
+impl Mount {
+    const fn new() -> Self {
+        Self {
+            in_use: false,
+            kind: MountKind::Tar,
+            path: "",
+            dev: 0,
+            files: [File::new(); FILES_MAX],
+            busy: false,
+            end_sector: 0,
+        }
     }
+}
 
-    let mut off = 0;
-    for i in 0..FILES_MAX {
-        let header = (&mut DISK[off] as *mut _ as *mut TarHeader)
-            .as_mut()
-            .unwrap();
+static mut MOUNTS: [Mount; MOUNTS_MAX] = [Mount::new(); MOUNTS_MAX];
+static mut BCACHE: BufferCache = BufferCache::new();
+
+// ブロックデバイス `dev` の tar アーカイブを `path` にマウントする。
+// ルートは "" で、それ以外は "/tools/" のように '/' で終わるパスを使う
+pub unsafe fn fs_mount(dev: usize, path: &'static str) -> Result<(), FsError> {
+    let m = mount_alloc(path);
+    let mount = &mut MOUNTS[m];
+    mount.dev = dev;
 
-        let name = &core::str::from_utf8(&header.name).unwrap();
-        if name.is_empty() {
+    let mut sector: u64 = 0;
+    for i in 0..FILES_MAX {
+        let buf = BCACHE.read(dev, sector)?;
+        let header = (buf.data.as_ptr() as *const TarHeader).as_ref().unwrap();
+        if header.name[0] == b'\0' {
+            BCACHE.release(dev, sector);
+            mount.end_sector = sector + 2;
             break;
         }
 
@@ -92,40 +153,99 @@ pub unsafe fn fs_init(virtio: &mut Virtio) {
             core::mem::size_of_val(&header.size),
         );
 
-        let file = &mut FILES[i];
+        let mtime = oct2int(
+            &header.mtime as *const [u8] as *const u8,
+            core::mem::size_of_val(&header.mtime),
+        );
+
+        let file = &mut mount.files[i];
         file.in_use = true;
-        file.name[0..name.len()].copy_from_slice(name.as_bytes());
-        if filesz > 0 {
-            file.data[0..filesz].copy_from_slice(core::slice::from_raw_parts(
-                &header.data as *const [u8] as *const u8,
-                filesz,
-            ))
+        file.mount = m;
+        file.name.copy_from_slice(&header.name);
+        file.mtime = mtime as u64;
+        BCACHE.release(dev, sector);
+        sector += 1;
+
+        let nsectors = (align_up(filesz, SECTOR_SIZE) / SECTOR_SIZE) as u64;
+        BCACHE.prefetch(dev, sector, nsectors)?;
+
+        let mut off = 0;
+        while off < filesz {
+            let len = core::cmp::min(filesz - off, SECTOR_SIZE);
+            let buf = BCACHE.read(dev, sector)?;
+            file.data[off..(off + len)].copy_from_slice(&buf.data[0..len]);
+            BCACHE.release(dev, sector);
+            off += len;
+            sector += 1;
         }
         file.size = filesz;
-        println!(
-            "file: {}, size={}",
-            &core::str::from_utf8(&file.name).unwrap()[0..(ascii_len(&file.name as *const u8) - 1)],
-            file.size,
-        );
+        mount.end_sector = sector;
+        log!("file: {}{}, size={}", path, file.name(), file.size);
+    }
 
-        off += align_up(
-            core::mem::size_of::<TarHeader>() + filesz,
-            Virtio::SECTOR_SIZE as usize,
-        );
+    mount.in_use = true;
+    Ok(())
+}
+
+unsafe fn mount_alloc(path: &'static str) -> usize {
+    let m = match MOUNTS.iter().position(|m| !m.in_use) {
+        Some(m) => m,
+        None => panic!("fs: too many mounts"),
+    };
+    MOUNTS[m] = Mount::new();
+    MOUNTS[m].path = path;
+    m
+}
+
+// virtio-9p で共有されたホストのディレクトリを `path` にマウントする。
+// ファイルは開くたびにホストから読み直すので、ホスト側での変更もすぐに見える
+pub unsafe fn fs_mount_9p(path: &'static str) -> Result<(), FsError> {
+    p9_attach()?;
+    let m = mount_alloc(path);
+    MOUNTS[m].kind = MountKind::P9;
+    MOUNTS[m].in_use = true;
+    Ok(())
+}
+
+pub fn fs_is_read_only(file: &File) -> bool {
+    let mount = unsafe { &MOUNTS[file.mount] };
+    mount.kind == MountKind::Tar && block_device(mount.dev).is_read_only()
+}
+
+// tar のヘッダの数値は 0 埋めした 8 進数の文字列で、最後に NUL を置く
+fn write_oct(field: &mut [u8], mut value: u64) {
+    let len = field.len();
+    for i in 0..(len - 1) {
+        field[(len - 2) - i] = (value % 8) as u8 + b'0';
+        value /= 8;
     }
+    field[len - 1] = b'\0';
 }
 
-pub unsafe fn fs_flush(virtio: &mut Virtio) {
-    let mut off: usize = 0;
+// マウント `m` のファイルを tar アーカイブとしてデバイスに書き戻す
+pub unsafe fn fs_flush(m: usize) -> Result<(), FsError> {
+    if MOUNTS[m].kind == MountKind::P9 {
+        return p9_flush(m);
+    }
+
+    let mount = &mut MOUNTS[m];
+    let dev = mount.dev;
+    if block_device(dev).is_read_only() {
+        println!("fs: disk is read-only");
+        return Err(BlockError::ReadOnly.into());
+    }
+
+    let mut sector: u64 = 0;
+    let mut written = 0;
     for i in 0..FILES_MAX {
-        let file = &mut FILES[i];
+        let file = &mut mount.files[i];
         if !file.in_use {
             continue;
         }
 
-        let header = (&mut DISK[off] as *mut u8 as *mut TarHeader)
-            .as_mut()
-            .unwrap();
+        let buf = BCACHE.get(dev, sector)?;
+        buf.data.fill(0);
+        let header = (buf.data.as_mut_ptr() as *mut TarHeader).as_mut().unwrap();
         let name = &file.name;
         header.name[0..file.name.len()].copy_from_slice(name);
         let mode = b"0000644\0";
@@ -136,12 +256,8 @@ pub unsafe fn fs_flush(virtio: &mut Virtio) {
         header.version[0..version.len()].copy_from_slice(version);
         header.type_ = b'0';
 
-        let mut filesz = file.size;
-        for i in 0..(header.size.len() - 1) {
-            header.size[(header.size.len() - 2) - i] = (filesz % 8) as u8 + b'0';
-            filesz /= 8;
-        }
-        header.size[header.size.len() - 1] = b'\0';
+        write_oct(&mut header.size, file.size as u64);
+        write_oct(&mut header.mtime, file.mtime);
 
         // チェックサムを計算
         let mut checksum = b' ' as usize * core::mem::size_of_val(&header.checksum);
@@ -165,43 +281,205 @@ pub unsafe fn fs_flush(virtio: &mut Virtio) {
             header.checksum[(header.checksum.len() - 3) - i] = (checksum % 8) as u8 + b'0';
             checksum /= 8;
         }
+        header.checksum[header.checksum.len() - 1] = b' ';
+
+        buf.mark_dirty();
+        BCACHE.release(dev, sector);
+        sector += 1;
 
-        let header_data =
-            core::slice::from_raw_parts_mut(&mut header.data as *mut [u8] as *mut u8, file.size);
-        if file.size > 0 {
-            header_data.copy_from_slice(&file.data[0..file.size]);
+        let mut off = 0;
+        while off < file.size {
+            let len = core::cmp::min(file.size - off, SECTOR_SIZE);
+            let buf = BCACHE.get(dev, sector)?;
+            buf.data.fill(0);
+            buf.data[0..len].copy_from_slice(&file.data[off..(off + len)]);
+            buf.mark_dirty();
+            BCACHE.release(dev, sector);
+            off += len;
+            sector += 1;
         }
 
-        off += align_up(
-            core::mem::size_of::<TarHeader>() + file.size,
-            Virtio::SECTOR_SIZE as usize,
-        );
+        written += align_up(core::mem::size_of::<TarHeader>() + file.size, SECTOR_SIZE);
     }
 
-    for sector in 0..(core::mem::size_of_val(&DISK) / Virtio::SECTOR_SIZE as usize) {
-        virtio.read_write_disk(
-            &mut DISK[(sector * Virtio::SECTOR_SIZE as usize)..],
-            sector as u64,
-            true,
-        );
+    // tar の終端を示す 2 つの空ブロック
+    for _ in 0..2 {
+        let buf = BCACHE.get(dev, sector)?;
+        buf.data.fill(0);
+        buf.mark_dirty();
+        BCACHE.release(dev, sector);
+        sector += 1;
+    }
+
+    BCACHE.flush(dev)?;
+
+    // ファイルが縮んだ分の古いデータをデバイスに破棄させる
+    if sector < mount.end_sector {
+        fs_trim(dev, sector, mount.end_sector - sector)?;
     }
+    mount.end_sector = sector;
 
-    println!("wrote {} bytes to disk", core::mem::size_of_val(&DISK));
+    log!("wrote {} bytes to disk", written);
+    Ok(())
 }
 
-pub fn fs_lookup(filename: &str) -> Result<*mut File, ()> {
-    for i in 0..FILES_MAX {
-        let file = unsafe { &FILES[i] };
-        // println!(
-        //     "file: {}, size={}",
-        //     &core::str::from_utf8(&file.name).unwrap()[0..(ascii_len(&file.name as *const u8) - 1)],
-        //     file.size,
-        // );
-        let name =
-            &core::str::from_utf8(&file.name).unwrap()[0..(ascii_len(&file.name as *const u8) - 1)];
-        if name == filename {
-            return Ok(file as *const File as *mut File);
+// 書き込み可能なすべてのマウントについてキャッシュの内容を書き戻し、
+// デバイスの書き込みキャッシュもフラッシュする
+pub unsafe fn fs_sync() -> Result<(), BlockError> {
+    for mount in MOUNTS
+        .iter()
+        .filter(|m| m.in_use && m.kind == MountKind::Tar)
+    {
+        if !block_device(mount.dev).is_read_only() {
+            BCACHE.flush(mount.dev)?;
+        }
+    }
+    Ok(())
+}
+
+unsafe fn fs_trim(dev: usize, sector: u64, count: u64) -> Result<(), BlockError> {
+    BCACHE.invalidate(dev, sector, count);
+    match block_device(dev).discard(sector, count) {
+        Ok(()) | Err(BlockError::Unsupported) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+// tar のファイル名は "./hello.txt" の形式なので、先頭の "./" は無視して比べる
+fn strip_dot_slash(name: &str) -> &str {
+    name.strip_prefix("./").unwrap_or(name)
+}
+
+pub fn fs_lookup(filename: &str) -> Result<*mut File, FsError> {
+    let mounts = unsafe { &mut MOUNTS };
+    // もっとも長いパスで一致したマウントを使う
+    let m = (0..MOUNTS_MAX)
+        .filter(|&m| mounts[m].in_use && filename.starts_with(mounts[m].path))
+        .max_by_key(|&m| mounts[m].path.len())
+        .ok_or(FsError::NotFound)?;
+    let mount = &mut mounts[m];
+
+    let filename = strip_dot_slash(&filename[mount.path.len()..]);
+    if mount.kind == MountKind::P9 {
+        // files[0] は 1 つしかないので、前の readfile や writefile が使い終わるまで待つ。
+        // ホストとのやりとりで眠っている間に、他のプロセスに上書きされないようにする
+        let busy = ptr::addr_of!(mount.busy);
+        wait_event(mount_chan(m), || unsafe {
+            !read_volatile(busy) || PM.signal_pending()
+        });
+        if mount.busy {
+            return Err(FsError::Interrupted);
+        }
+        mount.busy = true;
+        let result = p9_load(m, filename).map_err(FsError::from);
+        if result.is_err() {
+            mount_unbusy(m);
+        }
+        return result;
+    }
+    for file in mount.files.iter_mut() {
+        if file.in_use && strip_dot_slash(file.name()) == filename {
+            return Ok(file as *mut File);
+        }
+    }
+    Err(FsError::NotFound)
+}
+
+// fs_lookup で得たファイルを使い終わったら呼ぶ
+pub fn fs_release(file: &File) {
+    if unsafe { MOUNTS[file.mount].kind } == MountKind::P9 {
+        mount_unbusy(file.mount);
+    }
+}
+
+fn mount_chan(m: usize) -> usize {
+    unsafe { ptr::addr_of!(MOUNTS[m]) as usize }
+}
+
+fn mount_unbusy(m: usize) {
+    unsafe {
+        MOUNTS[m].busy = false;
+        PM.wakeup(mount_chan(m));
+    }
+}
+
+// ホストのファイルを読み込む。readfile や writefile は fs_lookup の直後に使い終わるので、
+// 直前に開いた 1 つだけを files[0] に置いておく。File に入りきらない部分は読み込まない
+fn p9_load(m: usize, filename: &str) -> Result<*mut File, P9Error> {
+    let mount = unsafe { &mut MOUNTS[m] };
+    if filename.len() >= mount.files[0].name.len() {
+        return Err(P9Error::Protocol);
+    }
+
+    let fid = p9_walk(filename)?;
+    let result = p9_open(fid, P9_O_RDONLY).and_then(|iounit| {
+        let file = &mut mount.files[0];
+        *file = File::new();
+        file.size = p9_read(fid, iounit, &mut file.data)?;
+        file.in_use = true;
+        file.mount = m;
+        file.name[0..filename.len()].copy_from_slice(filename.as_bytes());
+        log!("file: {}{}, size={}", mount.path, filename, file.size);
+        Ok(file as *mut File)
+    });
+    p9_clunk(fid)?;
+    result
+}
+
+// 直前に開いたファイルをホストに書き戻す
+unsafe fn p9_flush(m: usize) -> Result<(), FsError> {
+    let file = &MOUNTS[m].files[0];
+    if !file.in_use {
+        return Ok(());
+    }
+
+    let fid = p9_walk(file.name())?;
+    let result = p9_open(fid, P9_O_WRONLY | P9_O_TRUNC)
+        .and_then(|iounit| p9_write(fid, iounit, &file.data[0..file.size]));
+    p9_clunk(fid)?;
+    log!("wrote {} bytes to host", result?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{register_block_device, BlockDevice, RamDisk};
+
+    static mut TEST_DISK: Option<RamDisk> = None;
+
+    #[test_case]
+    fn write_oct_round_trips_through_oct2int() {
+        let mut field = [0xffu8; 12];
+        write_oct(&mut field, 1_700_000_000);
+        assert_eq!(&field, b"14524770400\0");
+        assert_eq!(oct2int(field.as_ptr(), field.len()), 1_700_000_000);
+    }
+
+    #[test_case]
+    fn mount_parses_tar_archive() {
+        // ヘッダ、データ、終端の 2 ブロックだけの tar を RAM ディスクに作る
+        let mut image = [0u8; 4 * SECTOR_SIZE];
+        let header = unsafe { &mut *(image.as_mut_ptr() as *mut TarHeader) };
+        header.name[..11].copy_from_slice(b"./hello.txt");
+        write_oct(&mut header.mode, 0o644);
+        write_oct(&mut header.size, 6);
+        write_oct(&mut header.mtime, 1_700_000_000);
+        header.type_ = b'0';
+        header.magic.copy_from_slice(b"ustar\0");
+        image[SECTOR_SIZE..SECTOR_SIZE + 6].copy_from_slice(b"hello\n");
+
+        unsafe {
+            let disk = TEST_DISK.insert(RamDisk::new(8));
+            disk.write(0, &image).unwrap();
+            let dev = register_block_device("ktest", disk);
+            fs_mount(dev, "/ktest/").unwrap();
+
+            let file = &*fs_lookup("/ktest/hello.txt").unwrap();
+            assert_eq!(file.size, 6);
+            assert_eq!(&file.data[..6], b"hello\n");
+            assert_eq!(file.mtime, 1_700_000_000);
+            assert!(fs_lookup("/ktest/missing.txt").is_err());
         }
     }
-    Err(())
 }
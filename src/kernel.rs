@@ -2,25 +2,83 @@
 #![no_main]
 #![feature(naked_functions)]
 #![feature(asm_const)]
+// cargo test でカーネルのテストを QEMU 上で実行する (ktest.sh)
+#![cfg_attr(test, feature(custom_test_frameworks))]
+#![cfg_attr(test, test_runner(crate::ktest::run_tests))]
+#![cfg_attr(test, reexport_test_harness_main = "test_main")]
 
+mod bcache;
+mod block;
+mod console;
+mod fdt;
 mod fs;
+#[cfg(test)]
+mod ktest;
 mod memory;
+mod net;
+mod p9;
+mod plic;
+mod power;
 mod process;
+mod random;
+mod rtc;
 mod sbi;
+mod smp;
+mod tcp;
+mod timer;
+mod tty;
+mod uart;
 mod virtio;
+mod virtio_9p;
+mod virtio_blk;
+mod virtio_console;
+mod virtio_input;
+mod virtio_net;
+mod virtio_rng;
 
 use common::{
-    ascii_len, println, read_csr, write_csr, TrapFrame, SYS_EXIT, SYS_GETCHAR, SYS_PUTCHAR,
-    SYS_READFILE, SYS_WRITEFILE,
+    ascii_len, println, read_csr, write_csr, KeyEvent, SockAddr, SockMsg, Termios, Timespec,
+    TrapFrame, EINTR, READKEY_NONBLOCK, SYS_ACCEPT, SYS_BIND, SYS_CLOCK_GETTIME, SYS_CLOSE,
+    SYS_CONNECT, SYS_EXIT, SYS_GETCHAR, SYS_GETPID, SYS_GETRANDOM, SYS_KILL, SYS_LISTEN,
+    SYS_PUTCHAR, SYS_READFILE, SYS_READKEY, SYS_REBOOT, SYS_RECV, SYS_RECVFROM, SYS_SEND,
+    SYS_SENDTO, SYS_SHUTDOWN, SYS_SIGACTION, SYS_SIGRETURN, SYS_SLEEP, SYS_SOCKET, SYS_TCGETATTR,
+    SYS_TCSETATTR, SYS_WRITEFILE,
 };
-use core::{arch::asm, panic::PanicInfo, ptr};
-use fs::fs_flush;
-use process::ProcessManager;
-use sbi::{getchar, putchar};
+use console::console_flush;
+use core::{arch::asm, mem, panic::PanicInfo, ptr};
+use fs::{fs_flush, fs_is_read_only, fs_release, fs_sync, FsError};
+use plic::{plic_claim, plic_complete, plic_init};
+use process::{is_user_range, ProcessManager};
 
 use crate::{
-    fs::{fs_init, fs_lookup},
-    virtio::Virtio,
+    block::find_block_device,
+    fdt::{fdt_parse, Platform},
+    fs::{fs_lookup, fs_mount, fs_mount_9p},
+    memory::memory_init,
+    net::{
+        net_accept, net_bind, net_close, net_connect, net_init, net_listen, net_poll, net_recv,
+        net_recvfrom, net_send, net_sendto, net_socket, NetError,
+    },
+    power::{power_off, reboot},
+    random::{random_fill, random_init, RANDOM_DEVICE_PATH},
+    rtc::rtc_init,
+    sbi::sbi_init,
+    smp::{kernel_lock, kernel_unlock, smp_start_harts, HARTS_MAX},
+    timer::{
+        clock_gettime, clock_realtime, ms_to_ticks, ticks, timer_handle_interrupt, timer_init,
+    },
+    tty::{tty_getattr, tty_getchar, tty_poll, tty_putchar, tty_set_foreground, tty_setattr},
+    uart::{uart_handle_interrupt, uart_init, uart_irq},
+    virtio::{
+        virtio_irq_device, virtio_probe, virtio_wait_until, VIRTIO_DEVICE_9P, VIRTIO_DEVICE_BLK,
+        VIRTIO_DEVICE_CONSOLE, VIRTIO_DEVICE_INPUT, VIRTIO_DEVICE_NET, VIRTIO_DEVICE_RNG,
+    },
+    virtio_9p::{virtio_9p, virtio_9p_handle_interrupt, virtio_9p_init},
+    virtio_blk::{virtio_blk_handle_interrupt, virtio_blk_init},
+    virtio_console::{virtio_console_handle_interrupt, virtio_console_init},
+    virtio_input::{virtio_input, virtio_input_handle_interrupt, virtio_input_init},
+    virtio_net::{virtio_net_handle_interrupt, virtio_net_init},
+    virtio_rng::virtio_rng_init,
 };
 
 extern "C" {
@@ -31,50 +89,175 @@ extern "C" {
     static _binary_shell_bin_size: u32;
 }
 
+const SCAUSE_INTERRUPT: u32 = 1 << 31;
 const SCAUSE_ECALL: u32 = 8;
+const SCAUSE_SSI: u32 = SCAUSE_INTERRUPT | 1;
+const SCAUSE_STI: u32 = SCAUSE_INTERRUPT | 5;
+const SCAUSE_SEI: u32 = SCAUSE_INTERRUPT | 9;
+const SIE_SSIE: u32 = 1 << 1;
+const SIE_STIE: u32 = 1 << 5;
+const SIE_SEIE: u32 = 1 << 9;
+const SIP_SSIP: u32 = 1 << 1;
+const SIP_STIP: u32 = 1 << 5;
+// カーネルからユーザーのメモリを読み書きできるようにする
+const SSTATUS_SUM: u32 = 1 << 18;
 
 static mut PM: ProcessManager = ProcessManager::new();
-static mut VIRTIO: *mut Virtio = core::ptr::null_mut();
+static mut PLATFORM: Platform = Platform::new();
 
+// OpenSBI から a0 に hart ID、a1 にデバイスツリーのアドレスが渡される
 #[no_mangle]
-fn kernel_main() {
+extern "C" fn kernel_main(hartid: usize, dtb: usize) {
     unsafe {
         let bss = ptr::addr_of_mut!(__bss);
         let bss_end = ptr::addr_of!(__bss_end);
         ptr::write_bytes(bss, 0, bss_end as usize - bss as usize);
     }
+    // 他の hart を起こすまで手放さないので、初期化は 1 つの hart だけで進む
+    kernel_lock();
+
+    let platform = unsafe {
+        PLATFORM = fdt_parse(dtb as *const u8);
+        PLATFORM
+    };
+    memory_init(platform.ram_end(), platform.dtb, platform.dtb_size);
+    sbi_init();
+    // hart ごとの状態は HARTS_MAX 個分しかない。OpenSBI は -smp の数によっては
+    // 大きな ID の hart でカーネルを起動するので、そのときは先に進まずに止める
+    if hartid >= HARTS_MAX {
+        println!("boot: hart {hartid} is out of range; run with -smp {HARTS_MAX} or less");
+        power_off(1);
+    }
+    println!(
+        "boot: hart {}/{}, ram {:x}-{:x}, timebase {} Hz, plic {:x}, {} virtio-mmio slots",
+        hartid,
+        platform.hart_count,
+        platform.ram_base,
+        platform.ram_end(),
+        platform.timebase_freq,
+        platform.plic.base,
+        platform.virtio_count,
+    );
+    if let Some(uart) = platform.uart {
+        println!("boot: uart {:x} irq {}", uart.base, uart.irq);
+    }
+    if let Some(rtc) = platform.rtc {
+        println!("boot: rtc {:x}", rtc.base);
+    }
 
     write_csr!("stvec", kernel_entry);
 
-    // let mut buf: [u8; Virtio::SECTOR_SIZE as usize] = [0; Virtio::SECTOR_SIZE as usize];
-    let mut virtio = Virtio::new();
-    unsafe {
-        VIRTIO = core::ptr::addr_of_mut!(virtio) as *mut Virtio;
+    plic_init(hartid);
+    timer_init();
+    write_csr!("sie", read_csr!("sie") | SIE_SEIE | SIE_STIE | SIE_SSIE);
+    uart_init();
+    rtc_init();
+
+    for (slot, &device_id) in virtio_probe().iter().enumerate() {
+        match device_id {
+            0 => {}
+            VIRTIO_DEVICE_BLK => {
+                virtio_blk_init(slot);
+            }
+            VIRTIO_DEVICE_CONSOLE => virtio_console_init(slot),
+            VIRTIO_DEVICE_NET => virtio_net_init(slot),
+            VIRTIO_DEVICE_RNG => virtio_rng_init(slot),
+            VIRTIO_DEVICE_9P => virtio_9p_init(slot),
+            VIRTIO_DEVICE_INPUT => virtio_input_init(slot),
+            _ => println!("virtio: slot {slot}: unsupported device id {device_id}"),
+        }
+    }
+    random_init();
+    net_init();
+
+    // 1 台目のディスクをルートに、2 台目があれば /tools/ にマウントする
+    let root = match find_block_device("vda") {
+        Some(dev) => dev,
+        None => panic!("fs: no disk found"),
+    };
+    if let Err(err) = unsafe { fs_mount(root, "") } {
+        panic!("fs: failed to load disk: {err:?}");
+    }
+    if let Some(dev) = find_block_device("vdb") {
+        if let Err(err) = unsafe { fs_mount(dev, "/tools/") } {
+            println!("fs: failed to mount vdb: {err:?}");
+        }
+    }
+    // -virtfs で渡されたホストのディレクトリは /host/ から見える
+    if let Some(p9) = virtio_9p() {
+        match unsafe { fs_mount_9p("/host/") } {
+            Ok(()) => println!("fs: mounted 9p share {} at /host/", p9.tag()),
+            Err(err) => println!("fs: failed to mount 9p share: {err:?}"),
+        }
     }
-    // virtio.read_write_disk(&mut buf, 0, false);
-    // let s = core::str::from_utf8(&buf).unwrap();
-    // println!("lorem.txt {:?}", s);
-    //
-    // let mut buf: [u8; Virtio::SECTOR_SIZE as usize] = [0; Virtio::SECTOR_SIZE as usize];
-    // let message = "hello from kernel!!!\n";
-    // for (i, &byte) in message.as_bytes().iter().enumerate() {
-    //     buf[i] = byte;
-    // }
-    // virtio.read_write_disk(&mut buf, 0, true);
-    unsafe { fs_init(&mut virtio) };
 
     unsafe {
         let start = ptr::addr_of!(_binary_shell_bin_start);
         let size = ptr::addr_of!(_binary_shell_bin_size) as usize;
 
         PM.init();
-        PM.create(start, size);
+        // テストはデバイスとファイルシステムの準備ができたところで実行し、終わったら電源を切る
+        #[cfg(test)]
+        test_main();
+        smp_start_harts(secondary_boot as usize);
+        let shell = PM.create(start, size);
+        tty_set_foreground(shell);
         PM.yield_();
     }
 
     println!("switched to idle process");
+    idle();
+}
 
-    loop {}
+// HSM で起こされた hart は a0 に hart ID、a1 に smp_start_harts が用意したスタックを受け取る
+#[naked]
+extern "C" fn secondary_boot() {
+    unsafe {
+        asm!(
+            "mv tp, a0",
+            "mv sp, a1",
+            "j {main}",
+            main = sym secondary_main,
+            options(noreturn)
+        );
+    }
+}
+
+extern "C" fn secondary_main(hartid: usize) {
+    kernel_lock();
+    write_csr!("stvec", kernel_entry);
+    // デバイスの割り込みは起動した hart が受けるので、タイマーと IPI だけ有効にする
+    write_csr!("sstatus", SSTATUS_SUM);
+    timer_init();
+    write_csr!("sie", read_csr!("sie") | SIE_STIE | SIE_SSIE);
+    println!("smp: hart {hartid} online");
+    idle();
+}
+
+// 動けるプロセスがないときはここに来る。割り込みで誰かが起きるまで待つ。
+// プロセスがすべて終了したら電源を切る
+fn idle() -> ! {
+    loop {
+        unsafe { PM.yield_() };
+        if unsafe { !PM.has_processes() } {
+            println!("all processes exited");
+            shutdown(false);
+        }
+        wait_for_interrupt();
+    }
+}
+
+// ディスクに書き戻してから電源を切るか再起動する
+fn shutdown(restart: bool) -> ! {
+    if let Err(err) = unsafe { fs_sync() } {
+        println!("fs: failed to sync: {err:?}");
+    }
+    println!("{}", if restart { "rebooting" } else { "powering off" });
+    console_flush();
+    if restart {
+        reboot();
+    }
+    power_off(0);
 }
 
 #[link_section = ".text.boot"]
@@ -84,6 +267,7 @@ extern "C" fn boot() {
     unsafe {
         asm!(
             "la sp, {stack_top}",
+            "mv tp, a0",
             "j kernel_main",
             stack_top = sym  __stack_top,
             options(noreturn)
@@ -91,10 +275,18 @@ extern "C" fn boot() {
     }
 }
 
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ktest::test_panic(info);
+}
+
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("PANIC: {info}");
-    loop {}
+    console_flush();
+    power_off(1);
 }
 
 #[naked]
@@ -136,6 +328,8 @@ extern "C" fn kernel_entry() {
             "sw s11, 4 * 29(sp)",
             "csrr a0, sscratch",
             "sw a0, 4 * 30(sp)",
+            // ユーザーの tp は保存したので、カーネルスタックの一番上に置いた hart ID に替える
+            "lw tp, 4 * 31(sp)",
             "addi a0, sp, 4*31",
             "csrw sscratch, a0",
             "mv a0, sp",
@@ -179,36 +373,105 @@ extern "C" fn kernel_entry() {
 
 #[no_mangle]
 fn handle_trap(f: *mut TrapFrame) {
+    kernel_lock();
     let scause = read_csr!("scause");
     let stval = read_csr!("stval");
     let mut user_pc = read_csr!("sepc");
 
     if scause == SCAUSE_ECALL {
-        handle_syscall(f);
-        user_pc += 4;
+        // sigreturn はハンドラを呼ぶ前のレジスタと pc にそのまま戻る
+        match unsafe { (*f).a3 } {
+            SYS_SIGRETURN => match unsafe { PM.sigreturn(f.as_mut().unwrap()) } {
+                Some(pc) => user_pc = pc,
+                // ハンドラの外から呼ばれたら、普通のシステムコールのエラーとして返す
+                None => {
+                    unsafe { (*f).a0 = 0xffff_ffff };
+                    user_pc += 4;
+                }
+            },
+            _ => {
+                handle_syscall(f);
+                user_pc += 4;
+            }
+        }
+    } else if scause == SCAUSE_SEI {
+        handle_external_interrupt();
+    } else if scause == SCAUSE_STI {
+        timer_handle_interrupt();
+    } else if scause == SCAUSE_SSI {
+        // アイドルの hart を wfi から起こすための IPI なので、消すだけでよい
+        write_csr!("sip", read_csr!("sip") & !SIP_SSIP);
     } else {
         panic!("unexpected trap scause={scause:x}, stval={stval:x}, sepc={user_pc:x}");
     }
 
+    unsafe { PM.deliver_signals(f.as_mut().unwrap(), &mut user_pc) };
     write_csr!("sepc", user_pc);
+    kernel_unlock();
+}
+
+fn handle_external_interrupt() {
+    loop {
+        let irq = plic_claim();
+        if irq == 0 {
+            break;
+        }
+
+        match virtio_irq_device(irq) {
+            Some((slot, VIRTIO_DEVICE_BLK)) => virtio_blk_handle_interrupt(slot),
+            Some((slot, VIRTIO_DEVICE_CONSOLE)) => virtio_console_handle_interrupt(slot),
+            Some((slot, VIRTIO_DEVICE_9P)) => virtio_9p_handle_interrupt(slot),
+            Some((slot, VIRTIO_DEVICE_INPUT)) => virtio_input_handle_interrupt(slot),
+            Some((slot, VIRTIO_DEVICE_NET)) => {
+                virtio_net_handle_interrupt(slot);
+                net_poll();
+            }
+            _ if uart_irq() == Some(irq) => uart_handle_interrupt(),
+            _ => println!("unexpected irq {irq}"),
+        }
+        plic_complete(irq);
+    }
+    tty_poll();
+}
+
+// カーネル内では割り込みが無効なので、wfi で待ってから自分で処理する。
+// 待っている間は他の hart がカーネルに入れるようにロックを手放す
+pub fn wait_for_interrupt() {
+    kernel_unlock();
+    unsafe { asm!("wfi") };
+    kernel_lock();
+    write_csr!("sip", read_csr!("sip") & !SIP_SSIP);
+    if read_csr!("sip") & SIP_STIP != 0 {
+        timer_handle_interrupt();
+    }
+    handle_external_interrupt();
+}
+
+// ユーザーから渡されたポインタを参照にする。NULL やユーザー空間の外を指していれば None
+fn user_ref<'a, T>(addr: u32) -> Option<&'a mut T> {
+    if !is_user_range(addr, mem::size_of::<T>()) || addr as usize % mem::align_of::<T>() != 0 {
+        return None;
+    }
+    unsafe { (addr as *mut T).as_mut() }
+}
+
+fn user_slice<'a>(addr: u32, len: u32) -> Option<&'a mut [u8]> {
+    if !is_user_range(addr, len as usize) {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) })
 }
 
 fn handle_syscall(f: *mut TrapFrame) {
     let f = unsafe { f.as_mut().unwrap() };
     match f.a3 {
-        SYS_PUTCHAR => putchar(f.a0 as u8),
-        SYS_GETCHAR => loop {
-            let ch = getchar();
-            if ch >= 0 {
-                f.a0 = ch as u32;
-                break;
-            }
-
-            unsafe { PM.yield_() };
-        },
+        SYS_PUTCHAR => tty_putchar(f.a0 as u8),
+        SYS_GETCHAR => f.a0 = tty_getchar(),
         SYS_EXIT => {
             unsafe { PM.exit() };
         }
+        SYS_SHUTDOWN => shutdown(false),
+        SYS_REBOOT => shutdown(true),
         SYS_READFILE => {
             let filename = f.a0 as *const u8;
             let filename_len = ascii_len(filename);
@@ -220,12 +483,29 @@ fn handle_syscall(f: *mut TrapFrame) {
             let buf = f.a1 as *const u8;
             let mut len = f.a2 as usize;
 
-            let file = if let Ok(f) = fs_lookup(filename) {
-                unsafe { f.as_mut().unwrap() }
-            } else {
-                println!("file not found: {}", filename);
-                f.a0 = 0xffff_fffe as u32;
+            // /dev/random はディスク上のファイルではなく、乱数を返す
+            if filename == RANDOM_DEVICE_PATH {
+                f.a0 = match user_slice(f.a1, f.a2) {
+                    Some(buf) => {
+                        random_fill(buf);
+                        len as u32
+                    }
+                    None => 0xffff_ffff,
+                };
                 return;
+            }
+
+            let file = match fs_lookup(filename) {
+                Ok(file) => unsafe { file.as_mut().unwrap() },
+                Err(FsError::Interrupted) => {
+                    f.a0 = EINTR;
+                    return;
+                }
+                Err(_) => {
+                    println!("file not found: {}", filename);
+                    f.a0 = 0xffff_fffe as u32;
+                    return;
+                }
             };
 
             if len > file.size {
@@ -233,6 +513,7 @@ fn handle_syscall(f: *mut TrapFrame) {
             }
 
             unsafe { ptr::copy(file.data.as_ptr(), buf as *mut _, len) };
+            fs_release(file);
             f.a0 = len as u32;
         }
         SYS_WRITEFILE => {
@@ -246,26 +527,239 @@ fn handle_syscall(f: *mut TrapFrame) {
             let buf = f.a1 as *const u8;
             let mut len = f.a2 as usize;
 
-            let file = if let Ok(f) = fs_lookup(filename) {
-                unsafe { f.as_mut().unwrap() }
-            } else {
-                println!("file not found: {}", filename);
-                f.a0 = 0xffff_fffe as u32;
-                return;
+            let file = match fs_lookup(filename) {
+                Ok(file) => unsafe { file.as_mut().unwrap() },
+                Err(FsError::Interrupted) => {
+                    f.a0 = EINTR;
+                    return;
+                }
+                Err(_) => {
+                    println!("file not found: {}", filename);
+                    f.a0 = 0xffff_fffe as u32;
+                    return;
+                }
             };
 
+            if fs_is_read_only(file) {
+                println!("read-only file system: {}", filename);
+                fs_release(file);
+                f.a0 = 0xffff_ffff as u32;
+                return;
+            }
+
             if len > file.size {
                 len = file.size;
             }
 
             unsafe { ptr::copy(buf as *mut _, file.data.as_mut_ptr(), len) };
             file.size = len;
-            unsafe {
-                let virtio = VIRTIO.as_mut().unwrap();
-                fs_flush(virtio);
+            file.mtime = clock_realtime().sec;
+            let result = unsafe { fs_flush(file.mount) };
+            fs_release(file);
+            if let Err(err) = result {
+                println!("failed to write disk: {:?}", err);
+                f.a0 = 0xffff_ffff as u32;
+                return;
             }
             f.a0 = len as u32;
         }
+        SYS_SOCKET => {
+            f.a0 = match net_socket(f.a0) {
+                Ok(fd) => fd as u32,
+                Err(_) => 0xffff_ffff,
+            };
+        }
+        SYS_BIND => {
+            let Some(addr) = user_ref::<SockAddr>(f.a1) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            f.a0 = match net_bind(f.a0 as usize, addr) {
+                Ok(()) => 0,
+                Err(_) => 0xffff_ffff,
+            };
+        }
+        SYS_SENDTO => {
+            let Some(msg) = user_ref::<SockMsg>(f.a1) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            let Some(buf) = user_slice(msg.buf, msg.len) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            f.a0 = match net_sendto(f.a0 as usize, buf, &msg.addr) {
+                Ok(len) => len as u32,
+                Err(err) => {
+                    println!("sendto failed: {:?}", err);
+                    0xffff_ffff
+                }
+            };
+        }
+        SYS_RECVFROM => {
+            let Some(msg) = user_ref::<SockMsg>(f.a1) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            let Some(buf) = user_slice(msg.buf, msg.len) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            f.a0 = match net_recvfrom(f.a0 as usize, buf) {
+                Ok((len, from)) => {
+                    msg.len = len as u32;
+                    msg.addr = from;
+                    len as u32
+                }
+                Err(NetError::Interrupted) => EINTR,
+                Err(_) => 0xffff_ffff,
+            };
+        }
+        SYS_LISTEN => {
+            f.a0 = match net_listen(f.a0 as usize, f.a1 as usize) {
+                Ok(()) => 0,
+                Err(_) => 0xffff_ffff,
+            };
+        }
+        SYS_ACCEPT => {
+            // NULL なら接続元を返さない
+            let addr = user_ref::<SockAddr>(f.a1);
+            f.a0 = match net_accept(f.a0 as usize) {
+                Ok((fd, from)) => {
+                    if let Some(addr) = addr {
+                        *addr = from;
+                    }
+                    fd as u32
+                }
+                Err(NetError::Interrupted) => EINTR,
+                Err(_) => 0xffff_ffff,
+            };
+        }
+        SYS_CONNECT => {
+            let Some(addr) = user_ref::<SockAddr>(f.a1) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            f.a0 = match net_connect(f.a0 as usize, addr) {
+                Ok(()) => 0,
+                Err(NetError::Interrupted) => EINTR,
+                Err(err) => {
+                    println!("connect failed: {:?}", err);
+                    0xffff_ffff
+                }
+            };
+        }
+        SYS_SEND => {
+            let Some(buf) = user_slice(f.a1, f.a2) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            f.a0 = match net_send(f.a0 as usize, buf) {
+                Ok(len) => len as u32,
+                Err(NetError::Interrupted) => EINTR,
+                Err(_) => 0xffff_ffff,
+            };
+        }
+        SYS_RECV => {
+            let Some(buf) = user_slice(f.a1, f.a2) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            f.a0 = match net_recv(f.a0 as usize, buf) {
+                Ok(len) => len as u32,
+                Err(NetError::Interrupted) => EINTR,
+                Err(_) => 0xffff_ffff,
+            };
+        }
+        SYS_GETRANDOM => {
+            let Some(buf) = user_slice(f.a0, f.a1) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            random_fill(buf);
+            f.a0 = buf.len() as u32;
+        }
+        SYS_READKEY => {
+            let Some(event) = user_ref::<KeyEvent>(f.a0) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            if virtio_input().is_none() {
+                f.a0 = 0xffff_ffff;
+                return;
+            }
+            if f.a1 & READKEY_NONBLOCK == 0 {
+                let chan = virtio_input().unwrap().chan();
+                virtio_wait_until(chan, || {
+                    virtio_input().unwrap().has_event() || unsafe { PM.signal_pending() }
+                });
+            }
+            f.a0 = match virtio_input().unwrap().read_event() {
+                Some(e) => {
+                    *event = e;
+                    1
+                }
+                None => 0,
+            };
+        }
+        SYS_TCGETATTR => {
+            f.a0 = match user_ref::<Termios>(f.a0) {
+                Some(termios) => {
+                    *termios = tty_getattr();
+                    0
+                }
+                None => 0xffff_ffff,
+            };
+        }
+        SYS_TCSETATTR => {
+            f.a0 = match user_ref::<Termios>(f.a0) {
+                Some(termios) => {
+                    tty_setattr(termios);
+                    0
+                }
+                None => 0xffff_ffff,
+            };
+        }
+        SYS_GETPID => f.a0 = unsafe { PM.current_pid() },
+        SYS_CLOCK_GETTIME => {
+            let Some(ts) = user_ref::<Timespec>(f.a1) else {
+                f.a0 = 0xffff_ffff;
+                return;
+            };
+            f.a0 = match clock_gettime(f.a0) {
+                Some(now) => {
+                    *ts = now;
+                    0
+                }
+                None => 0xffff_ffff,
+            };
+        }
+        SYS_SLEEP => {
+            // シグナルで起こされたら残りを待たずに戻る
+            let deadline = ticks() + ms_to_ticks(f.a0 as u64);
+            while ticks() < deadline && !unsafe { PM.signal_pending() } {
+                unsafe { PM.sleep(0, Some(deadline)) };
+            }
+            f.a0 = if ticks() < deadline { EINTR } else { 0 };
+        }
+        SYS_KILL => {
+            f.a0 = match unsafe { PM.kill(f.a0, f.a1) } {
+                Ok(()) => 0,
+                Err(()) => 0xffff_ffff,
+            };
+        }
+        SYS_SIGACTION => {
+            f.a0 = match unsafe { PM.sigaction(f.a0, f.a1, f.a2) } {
+                Ok(old) => old,
+                Err(()) => 0xffff_ffff,
+            };
+        }
+        SYS_CLOSE => {
+            f.a0 = match net_close(f.a0 as usize) {
+                Ok(()) => 0,
+                Err(_) => 0xffff_ffff,
+            };
+        }
         _ => panic!("unexpected syscall a3={:x}", f.a3 as u32),
     }
 }
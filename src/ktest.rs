@@ -0,0 +1,56 @@
+// `cargo test` で組み込まれるカーネルのテスト。
+// #[test_case] を付けた関数を起動の途中で順に実行し、結果を 1 行ずつ表示して
+// sifive_test で QEMU を終了させる。終了コードは成功なら 0、失敗なら 1
+use core::panic::PanicInfo;
+
+use common::{print, println};
+
+use crate::{console::console_flush, power::power_off};
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        let name = core::any::type_name::<T>();
+        unsafe { RUNNING = true };
+        print!("ktest: {name} ... ");
+        self();
+        println!("ok");
+        unsafe {
+            RUNNING = false;
+            PASSED += 1;
+        }
+    }
+}
+
+// テストを実行中か、ここまでに成功したテストの数
+static mut RUNNING: bool = false;
+static mut PASSED: usize = 0;
+
+pub fn run_tests(tests: &[&dyn Testable]) {
+    println!("ktest: running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    println!("ktest: result: ok. {} passed; 0 failed", unsafe { PASSED });
+    console_flush();
+    power_off(0);
+}
+
+// アサーションが失敗するとパニックになるので、残りのテストは実行せずに終わる
+pub fn test_panic(info: &PanicInfo) -> ! {
+    // テスト名の行の続きに FAILED と書く
+    if unsafe { RUNNING } {
+        println!("FAILED");
+    } else {
+        println!("ktest: panicked outside of a test");
+    }
+    println!("ktest: {info}");
+    println!("ktest: result: FAILED. {} passed; 1 failed", unsafe {
+        PASSED
+    });
+    console_flush();
+    power_off(1);
+}
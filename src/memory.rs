@@ -1,10 +1,9 @@
 use core::ptr;
 
-use common::{is_aligned, PAddr, VAddr, PAGE_SIZE};
+use common::{align_up, is_aligned, PAddr, VAddr, PAGE_SIZE};
 
 extern "C" {
     static mut __free_ram: u8;
-    static mut __free_ram_end: u8;
 }
 
 pub const SATP_SV32: u32 = 1 << 31;
@@ -15,13 +14,34 @@ pub const PAGE_X: u32 = 1 << 3;
 pub const PAGE_U: u32 = 1 << 4;
 
 static mut NEXT_PADDR: *mut u8 = unsafe { ptr::addr_of_mut!(__free_ram) };
+static mut FREE_RAM_END: *mut u8 = ptr::null_mut();
+// 割り当ててはいけない領域 (デバイスツリーなど)
+static mut RESERVED: (usize, usize) = (0, 0);
+
+// RAM の終端はデバイスツリーから分かるので、ページを割り当てる前に呼ぶ
+pub fn memory_init(ram_end: usize, reserved_start: usize, reserved_size: usize) {
+    unsafe {
+        FREE_RAM_END = ram_end as *mut u8;
+        RESERVED = (
+            reserved_start & !(PAGE_SIZE - 1),
+            align_up(reserved_start + reserved_size, PAGE_SIZE),
+        );
+    }
+}
 
 pub fn alloc_pages(n: usize) -> PAddr {
     unsafe {
+        let (reserved_start, reserved_end) = RESERVED;
+        if (NEXT_PADDR as usize) < reserved_end
+            && NEXT_PADDR as usize + n * PAGE_SIZE > reserved_start
+        {
+            NEXT_PADDR = reserved_end as *mut u8;
+        }
+
         let paddr = NEXT_PADDR as PAddr;
         NEXT_PADDR = NEXT_PADDR.add(n * PAGE_SIZE);
 
-        if NEXT_PADDR > ptr::addr_of_mut!(__free_ram_end) {
+        if NEXT_PADDR > FREE_RAM_END {
             panic!("out of memory");
         }
 
@@ -51,3 +71,55 @@ pub fn map_page(table1: u32, vaddr: VAddr, paddr: PAddr, flags: u32) {
         *(table0.offset(vpn0)) = ((paddr / PAGE_SIZE as u32) << 10) | flags | PAGE_V;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn alloc_pages_returns_zeroed_pages() {
+        let a = alloc_pages(2);
+        let b = alloc_pages(1);
+        assert!(is_aligned(a as usize, PAGE_SIZE));
+        assert_eq!(b, a + 2 * PAGE_SIZE as u32);
+        let bytes = unsafe { core::slice::from_raw_parts(a as *const u8, 3 * PAGE_SIZE) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+    }
+
+    #[test_case]
+    fn alloc_pages_skips_reserved_region() {
+        unsafe {
+            let saved = RESERVED;
+            let next = NEXT_PADDR as usize;
+            RESERVED = (next + PAGE_SIZE, next + 3 * PAGE_SIZE);
+            // 予約された領域にかかるので、その後ろから割り当てる
+            let paddr = alloc_pages(2);
+            RESERVED = saved;
+            assert_eq!(paddr as usize, next + 3 * PAGE_SIZE);
+        }
+    }
+
+    #[test_case]
+    fn map_page_writes_leaf_entry() {
+        let table1 = alloc_pages(1);
+        let paddr = alloc_pages(1);
+        let vaddr: VAddr = 0x0140_3000;
+        map_page(table1, vaddr, paddr, PAGE_R | PAGE_W);
+
+        unsafe {
+            let pte1 = *(table1 as *const u32).add((vaddr >> 22) as usize);
+            // 1 段目は次のページテーブルを指すだけで、R/W/X は立たない
+            assert_eq!(pte1 & (PAGE_V | PAGE_R | PAGE_W | PAGE_X), PAGE_V);
+            let table0 = ((pte1 >> 10) * PAGE_SIZE as u32) as *const u32;
+            let pte0 = *table0.add(((vaddr >> 12) & 0x3ff) as usize);
+            assert_eq!(
+                pte0,
+                ((paddr / PAGE_SIZE as u32) << 10) | PAGE_R | PAGE_W | PAGE_V
+            );
+
+            // 同じ 4 MiB の範囲なら 2 段目のテーブルを使い回す
+            map_page(table1, vaddr + PAGE_SIZE as u32, paddr, PAGE_R);
+            assert_eq!(*(table1 as *const u32).add((vaddr >> 22) as usize), pte1);
+        }
+    }
+}
@@ -0,0 +1,758 @@
+use common::{println, SockAddr, SOCK_DGRAM, SOCK_STREAM};
+use core::{mem, ptr};
+
+use crate::{
+    tcp::{
+        tcp_accept, tcp_close, tcp_connect, tcp_input, tcp_listen, tcp_next_deadline,
+        tcp_port_in_use, tcp_readable, tcp_recv, tcp_send, tcp_send_space, tcp_state, tcp_timer,
+        TcpState,
+    },
+    timer::{ms_to_ticks, ticks},
+    virtio_net::virtio_net,
+    PM,
+};
+
+pub type Ipv4Addr = [u8; 4];
+pub type MacAddr = [u8; 6];
+
+// QEMU のユーザーモードネットワーク (slirp) の既定値
+pub const NET_IP: Ipv4Addr = [10, 0, 2, 15];
+const NET_NETMASK: Ipv4Addr = [255, 255, 255, 0];
+const NET_GATEWAY: Ipv4Addr = [10, 0, 2, 2];
+
+const ETH_TYPE_IPV4: u16 = 0x0800;
+const ETH_TYPE_ARP: u16 = 0x0806;
+const ETH_FRAME_MAX: usize = 1514;
+const ETH_BROADCAST: MacAddr = [0xff; 6];
+
+const ARP_HTYPE_ETHERNET: u16 = 1;
+const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+const ARP_TABLE_SIZE: usize = 8;
+const ARP_RETRY: usize = 3;
+const ARP_TIMEOUT_MS: u64 = 500;
+
+const IP_PROTO_ICMP: u8 = 1;
+pub const IP_PROTO_TCP: u8 = 6;
+const IP_PROTO_UDP: u8 = 17;
+const IP_TTL: u8 = 64;
+const IP_FLAG_DF: u16 = 0x4000;
+const IP_FRAG_MASK: u16 = 0x3fff;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+pub const UDP_PAYLOAD_MAX: usize = ETH_FRAME_MAX
+    - mem::size_of::<EthHeader>()
+    - mem::size_of::<Ipv4Header>()
+    - mem::size_of::<UdpHeader>();
+const UDP_QUEUE_LEN: usize = 4;
+const UDP_EPHEMERAL_PORT: u16 = 49152;
+const SOCKETS_MAX: usize = 8;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum NetError {
+    NoDevice,
+    BadSocket,
+    AddrInUse,
+    Unreachable,
+    TooLarge,
+    Unsupported,
+    NoBuffers,
+    NotConnected,
+    Refused,
+    Reset,
+    Interrupted,
+}
+
+// 多バイトのフィールドはすべてネットワークバイトオーダーで持つ
+#[repr(C, packed)]
+struct EthHeader {
+    dst: MacAddr,
+    src: MacAddr,
+    type_: u16,
+}
+
+#[repr(C, packed)]
+struct ArpPacket {
+    htype: u16,
+    ptype: u16,
+    hlen: u8,
+    plen: u8,
+    oper: u16,
+    sha: MacAddr,
+    spa: Ipv4Addr,
+    tha: MacAddr,
+    tpa: Ipv4Addr,
+}
+
+#[repr(C, packed)]
+struct Ipv4Header {
+    ver_ihl: u8,
+    tos: u8,
+    len: u16,
+    id: u16,
+    frag: u16,
+    ttl: u8,
+    proto: u8,
+    checksum: u16,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+}
+
+#[repr(C, packed)]
+struct IcmpHeader {
+    type_: u8,
+    code: u8,
+    checksum: u16,
+    id: u16,
+    seq: u16,
+}
+
+#[repr(C, packed)]
+struct UdpHeader {
+    src_port: u16,
+    dst_port: u16,
+    len: u16,
+    checksum: u16,
+}
+
+#[derive(Copy, Clone)]
+struct Datagram {
+    from: SockAddr,
+    len: usize,
+    data: [u8; UDP_PAYLOAD_MAX],
+}
+
+#[derive(Copy, Clone)]
+struct Socket {
+    in_use: bool,
+    type_: u32,
+    port: u16,
+    // SOCK_STREAM のソケットが使っている TCP の接続 (listen 中のものを含む)
+    tcb: Option<usize>,
+    queue: [Datagram; UDP_QUEUE_LEN],
+    queue_head: usize,
+    queue_len: usize,
+}
+
+impl Socket {
+    const fn new() -> Self {
+        Self {
+            in_use: false,
+            type_: 0,
+            port: 0,
+            tcb: None,
+            queue: [Datagram {
+                from: SockAddr {
+                    addr: [0; 4],
+                    port: 0,
+                },
+                len: 0,
+                data: [0; UDP_PAYLOAD_MAX],
+            }; UDP_QUEUE_LEN],
+            queue_head: 0,
+            queue_len: 0,
+        }
+    }
+}
+
+static mut MAC: MacAddr = [0; 6];
+static mut ARP_TABLE: [Option<(Ipv4Addr, MacAddr)>; ARP_TABLE_SIZE] = [None; ARP_TABLE_SIZE];
+static mut ARP_NEXT: usize = 0;
+static mut IP_ID: u16 = 0;
+static mut NEXT_EPHEMERAL_PORT: u16 = UDP_EPHEMERAL_PORT;
+static mut SOCKETS: [Socket; SOCKETS_MAX] = [Socket::new(); SOCKETS_MAX];
+static mut RX_FRAME: [u8; ETH_FRAME_MAX] = [0; ETH_FRAME_MAX];
+static mut TX_FRAME: [u8; ETH_FRAME_MAX] = [0; ETH_FRAME_MAX];
+
+pub fn read_header<T>(buf: &[u8]) -> Option<T> {
+    if buf.len() < mem::size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) })
+}
+
+pub fn write_header<T>(buf: &mut [u8], header: T) -> usize {
+    unsafe { ptr::write_unaligned(buf.as_mut_ptr() as *mut T, header) };
+    mem::size_of::<T>()
+}
+
+// インターネットチェックサム。`sum` には擬似ヘッダなどの途中結果を渡せる
+pub fn checksum(segs: &[&[u8]], sum: u32) -> u16 {
+    let mut sum = sum;
+    let mut odd = None;
+    for seg in segs.iter() {
+        for &b in seg.iter() {
+            match odd.take() {
+                Some(hi) => sum += ((hi as u32) << 8) | b as u32,
+                None => odd = Some(b),
+            }
+        }
+    }
+    if let Some(hi) = odd {
+        sum += (hi as u32) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn is_local(addr: Ipv4Addr) -> bool {
+    (0..4).all(|i| addr[i] & NET_NETMASK[i] == NET_IP[i] & NET_NETMASK[i])
+}
+
+pub fn net_init() {
+    let Some(dev) = virtio_net() else {
+        return;
+    };
+    unsafe { MAC = dev.mac };
+    println!(
+        "net: ip {}.{}.{}.{}, gateway {}.{}.{}.{}",
+        NET_IP[0],
+        NET_IP[1],
+        NET_IP[2],
+        NET_IP[3],
+        NET_GATEWAY[0],
+        NET_GATEWAY[1],
+        NET_GATEWAY[2],
+        NET_GATEWAY[3],
+    );
+}
+
+// 受信済みのフレームをすべて処理する。割り込みハンドラと、受信を待つシステムコールから呼ばれる
+pub fn net_poll() {
+    let Some(dev) = virtio_net() else {
+        return;
+    };
+    unsafe {
+        while let Some(len) = dev.recv(&mut RX_FRAME) {
+            eth_input(&RX_FRAME[0..len]);
+        }
+    }
+    tcp_timer();
+    // 届いたパケットで待ち状態が変わったかもしれないので、待っているプロセスに確かめさせる
+    unsafe { PM.wakeup(net_chan()) };
+}
+
+// パケットの到着を待つプロセスが眠るチャネル
+fn net_chan() -> usize {
+    ptr::addr_of!(SOCKETS) as usize
+}
+
+// `cond` が成り立つまで受信したパケットを処理しながら待つ。
+// 受信割り込みか、再送タイマーの時刻になると起こされる。シグナルが届いたら待つのをやめる
+fn net_wait(mut cond: impl FnMut() -> bool) -> Result<(), NetError> {
+    loop {
+        net_poll();
+        if cond() {
+            return Ok(());
+        }
+        if unsafe { PM.signal_pending() } {
+            return Err(NetError::Interrupted);
+        }
+        unsafe { PM.sleep(net_chan(), tcp_next_deadline()) };
+    }
+}
+
+fn eth_input(frame: &[u8]) {
+    let Some(eth) = read_header::<EthHeader>(frame) else {
+        return;
+    };
+    let payload = &frame[mem::size_of::<EthHeader>()..];
+    match u16::from_be(eth.type_) {
+        ETH_TYPE_ARP => arp_input(payload),
+        ETH_TYPE_IPV4 => ip_input(eth.src, payload),
+        _ => {}
+    }
+}
+
+fn eth_output(dst: MacAddr, type_: u16, segs: &[&[u8]]) -> Result<(), NetError> {
+    let dev = virtio_net().ok_or(NetError::NoDevice)?;
+    let frame = unsafe { &mut TX_FRAME };
+    let mut len = write_header(
+        frame,
+        EthHeader {
+            dst,
+            src: unsafe { MAC },
+            type_: type_.to_be(),
+        },
+    );
+    for seg in segs.iter() {
+        if len + seg.len() > frame.len() {
+            return Err(NetError::TooLarge);
+        }
+        frame[len..(len + seg.len())].copy_from_slice(seg);
+        len += seg.len();
+    }
+    dev.send(&frame[0..len]);
+    Ok(())
+}
+
+fn arp_lookup(addr: Ipv4Addr) -> Option<MacAddr> {
+    unsafe {
+        ARP_TABLE
+            .iter()
+            .flatten()
+            .find(|(ip, _)| *ip == addr)
+            .map(|(_, mac)| *mac)
+    }
+}
+
+fn arp_update(addr: Ipv4Addr, mac: MacAddr) {
+    unsafe {
+        if let Some(entry) = ARP_TABLE.iter_mut().flatten().find(|(ip, _)| *ip == addr) {
+            entry.1 = mac;
+            return;
+        }
+        ARP_TABLE[ARP_NEXT] = Some((addr, mac));
+        ARP_NEXT = (ARP_NEXT + 1) % ARP_TABLE_SIZE;
+    }
+}
+
+fn arp_output(oper: u16, tha: MacAddr, tpa: Ipv4Addr) -> Result<(), NetError> {
+    let packet = ArpPacket {
+        htype: ARP_HTYPE_ETHERNET.to_be(),
+        ptype: ETH_TYPE_IPV4.to_be(),
+        hlen: 6,
+        plen: 4,
+        oper: oper.to_be(),
+        sha: unsafe { MAC },
+        spa: NET_IP,
+        tha,
+        tpa,
+    };
+    let mut buf = [0; mem::size_of::<ArpPacket>()];
+    write_header(&mut buf, packet);
+    let dst = if oper == ARP_OP_REQUEST {
+        ETH_BROADCAST
+    } else {
+        tha
+    };
+    eth_output(dst, ETH_TYPE_ARP, &[&buf])
+}
+
+fn arp_input(payload: &[u8]) {
+    let Some(arp) = read_header::<ArpPacket>(payload) else {
+        return;
+    };
+    if u16::from_be(arp.htype) != ARP_HTYPE_ETHERNET || u16::from_be(arp.ptype) != ETH_TYPE_IPV4 {
+        return;
+    }
+
+    arp_update(arp.spa, arp.sha);
+    if u16::from_be(arp.oper) == ARP_OP_REQUEST && arp.tpa == NET_IP {
+        let _ = arp_output(ARP_OP_REPLY, arp.sha, arp.spa);
+    }
+}
+
+// 応答が来るか時間切れになるまで眠って待つ
+fn arp_resolve(addr: Ipv4Addr) -> Result<MacAddr, NetError> {
+    let next_hop = if is_local(addr) { addr } else { NET_GATEWAY };
+    if let Some(mac) = arp_lookup(next_hop) {
+        return Ok(mac);
+    }
+
+    for _ in 0..ARP_RETRY {
+        arp_output(ARP_OP_REQUEST, [0; 6], next_hop)?;
+        let deadline = ticks() + ms_to_ticks(ARP_TIMEOUT_MS);
+        while ticks() < deadline {
+            net_poll();
+            if let Some(mac) = arp_lookup(next_hop) {
+                return Ok(mac);
+            }
+            unsafe { PM.sleep(net_chan(), Some(deadline)) };
+        }
+    }
+    Err(NetError::Unreachable)
+}
+
+fn ip_input(src_mac: MacAddr, packet: &[u8]) {
+    let Some(ip) = read_header::<Ipv4Header>(packet) else {
+        return;
+    };
+    let header_len = ((ip.ver_ihl & 0xf) as usize) * 4;
+    let total_len = u16::from_be(ip.len) as usize;
+    if ip.ver_ihl >> 4 != 4
+        || header_len < mem::size_of::<Ipv4Header>()
+        || header_len > total_len
+        || total_len > packet.len()
+    {
+        return;
+    }
+    if checksum(&[&packet[0..header_len]], 0) != 0 {
+        return;
+    }
+    // 断片化されたパケットは扱わない
+    if u16::from_be(ip.frag) & IP_FRAG_MASK & !IP_FLAG_DF != 0 {
+        return;
+    }
+    if ip.dst != NET_IP && ip.dst != [255; 4] {
+        return;
+    }
+    // 応答を返すときに ARP を待たなくて済むよう、次のホップの MAC アドレスを覚えておく
+    arp_update(
+        if is_local(ip.src) {
+            ip.src
+        } else {
+            NET_GATEWAY
+        },
+        src_mac,
+    );
+
+    let payload = &packet[header_len..total_len];
+    match ip.proto {
+        IP_PROTO_ICMP => icmp_input(src_mac, ip.src, payload),
+        IP_PROTO_UDP => udp_input(ip.src, payload),
+        IP_PROTO_TCP => tcp_input(ip.src, payload),
+        _ => {}
+    }
+}
+
+fn ip_header(dst: Ipv4Addr, proto: u8, payload_len: usize) -> [u8; mem::size_of::<Ipv4Header>()] {
+    let id = unsafe {
+        IP_ID = IP_ID.wrapping_add(1);
+        IP_ID
+    };
+    let mut buf = [0; mem::size_of::<Ipv4Header>()];
+    write_header(
+        &mut buf,
+        Ipv4Header {
+            ver_ihl: 0x45,
+            tos: 0,
+            len: ((mem::size_of::<Ipv4Header>() + payload_len) as u16).to_be(),
+            id: id.to_be(),
+            frag: IP_FLAG_DF.to_be(),
+            ttl: IP_TTL,
+            proto,
+            checksum: 0,
+            src: NET_IP,
+            dst,
+        },
+    );
+    let sum = checksum(&[&buf], 0);
+    buf[mem::offset_of!(Ipv4Header, checksum)..][0..2].copy_from_slice(&sum.to_be_bytes());
+    buf
+}
+
+// 宛先の MAC アドレスが分かっているとき (受信したパケットへの応答など) に使う
+fn ip_output(dst_mac: MacAddr, dst: Ipv4Addr, proto: u8, segs: &[&[u8]]) -> Result<(), NetError> {
+    let payload_len = segs.iter().map(|s| s.len()).sum();
+    let header = ip_header(dst, proto, payload_len);
+    let mut all: [&[u8]; 4] = [&[]; 4];
+    if segs.len() >= all.len() {
+        return Err(NetError::Unsupported);
+    }
+    all[0] = &header;
+    all[1..=segs.len()].copy_from_slice(segs);
+    eth_output(dst_mac, ETH_TYPE_IPV4, &all[0..=segs.len()])
+}
+
+pub fn ip_send(dst: Ipv4Addr, proto: u8, segs: &[&[u8]]) -> Result<(), NetError> {
+    let dst_mac = if dst == [255; 4] {
+        ETH_BROADCAST
+    } else {
+        arp_resolve(dst)?
+    };
+    ip_output(dst_mac, dst, proto, segs)
+}
+
+fn icmp_input(src_mac: MacAddr, src: Ipv4Addr, payload: &[u8]) {
+    let Some(icmp) = read_header::<IcmpHeader>(payload) else {
+        return;
+    };
+    if icmp.type_ != ICMP_ECHO_REQUEST || checksum(&[payload], 0) != 0 {
+        return;
+    }
+
+    let data = &payload[mem::size_of::<IcmpHeader>()..];
+    let mut header = [0; mem::size_of::<IcmpHeader>()];
+    write_header(
+        &mut header,
+        IcmpHeader {
+            type_: ICMP_ECHO_REPLY,
+            code: 0,
+            checksum: 0,
+            id: icmp.id,
+            seq: icmp.seq,
+        },
+    );
+    let sum = checksum(&[&header, data], 0);
+    header[mem::offset_of!(IcmpHeader, checksum)..][0..2].copy_from_slice(&sum.to_be_bytes());
+    let _ = ip_output(src_mac, src, IP_PROTO_ICMP, &[&header, data]);
+}
+
+// UDP と TCP のチェックサムに含める擬似ヘッダ
+pub fn pseudo_sum(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, len: usize) -> u32 {
+    let word = |a: Ipv4Addr, i: usize| ((a[i] as u32) << 8) | a[i + 1] as u32;
+    word(src, 0) + word(src, 2) + word(dst, 0) + word(dst, 2) + proto as u32 + len as u32
+}
+
+fn udp_input(src: Ipv4Addr, payload: &[u8]) {
+    let Some(udp) = read_header::<UdpHeader>(payload) else {
+        return;
+    };
+    let len = u16::from_be(udp.len) as usize;
+    if len < mem::size_of::<UdpHeader>() || len > payload.len() {
+        return;
+    }
+    let payload = &payload[0..len];
+    if udp.checksum != 0 && checksum(&[payload], pseudo_sum(src, NET_IP, IP_PROTO_UDP, len)) != 0 {
+        return;
+    }
+
+    let port = u16::from_be(udp.dst_port);
+    let sockets = unsafe { &mut SOCKETS };
+    let Some(sock) = sockets
+        .iter_mut()
+        .find(|s| s.in_use && s.type_ == SOCK_DGRAM && s.port == port)
+    else {
+        return;
+    };
+    if sock.queue_len == UDP_QUEUE_LEN {
+        // 受信キューがいっぱいなら捨てる
+        return;
+    }
+
+    let data = &payload[mem::size_of::<UdpHeader>()..];
+    let dgram = &mut sock.queue[(sock.queue_head + sock.queue_len) % UDP_QUEUE_LEN];
+    dgram.from = SockAddr {
+        addr: src,
+        port: u16::from_be(udp.src_port),
+    };
+    dgram.len = data.len();
+    dgram.data[0..data.len()].copy_from_slice(data);
+    sock.queue_len += 1;
+}
+
+fn socket(fd: usize) -> Result<&'static mut Socket, NetError> {
+    match unsafe { SOCKETS.get_mut(fd) } {
+        Some(sock) if sock.in_use => Ok(sock),
+        _ => Err(NetError::BadSocket),
+    }
+}
+
+fn port_in_use(port: u16) -> bool {
+    unsafe { SOCKETS.iter().any(|s| s.in_use && s.port == port) || tcp_port_in_use(port) }
+}
+
+fn alloc_ephemeral_port() -> u16 {
+    loop {
+        let port = unsafe {
+            let port = NEXT_EPHEMERAL_PORT;
+            NEXT_EPHEMERAL_PORT = NEXT_EPHEMERAL_PORT
+                .checked_add(1)
+                .unwrap_or(UDP_EPHEMERAL_PORT);
+            port
+        };
+        if !port_in_use(port) {
+            return port;
+        }
+    }
+}
+
+fn alloc_socket(type_: u32) -> Result<usize, NetError> {
+    let sockets = unsafe { &mut SOCKETS };
+    let fd = sockets
+        .iter()
+        .position(|s| !s.in_use)
+        .ok_or(NetError::NoBuffers)?;
+    sockets[fd] = Socket::new();
+    sockets[fd].in_use = true;
+    sockets[fd].type_ = type_;
+    Ok(fd)
+}
+
+fn stream_socket(fd: usize) -> Result<&'static mut Socket, NetError> {
+    let sock = socket(fd)?;
+    if sock.type_ != SOCK_STREAM {
+        return Err(NetError::Unsupported);
+    }
+    Ok(sock)
+}
+
+pub fn net_socket(type_: u32) -> Result<usize, NetError> {
+    if virtio_net().is_none() {
+        return Err(NetError::NoDevice);
+    }
+    if type_ != SOCK_DGRAM && type_ != SOCK_STREAM {
+        return Err(NetError::Unsupported);
+    }
+    alloc_socket(type_)
+}
+
+pub fn net_bind(fd: usize, addr: &SockAddr) -> Result<(), NetError> {
+    let sock = socket(fd)?;
+    if addr.port == 0 || sock.port != 0 || port_in_use(addr.port) {
+        return Err(NetError::AddrInUse);
+    }
+    sock.port = addr.port;
+    Ok(())
+}
+
+pub fn net_sendto(fd: usize, data: &[u8], to: &SockAddr) -> Result<usize, NetError> {
+    let sock = socket(fd)?;
+    if sock.type_ != SOCK_DGRAM {
+        return Err(NetError::Unsupported);
+    }
+    if data.len() > UDP_PAYLOAD_MAX {
+        return Err(NetError::TooLarge);
+    }
+
+    // bind されていなければ空いている一時ポートを割り当てる
+    if sock.port == 0 {
+        sock.port = alloc_ephemeral_port();
+    }
+
+    let len = mem::size_of::<UdpHeader>() + data.len();
+    let mut header = [0; mem::size_of::<UdpHeader>()];
+    write_header(
+        &mut header,
+        UdpHeader {
+            src_port: sock.port.to_be(),
+            dst_port: to.port.to_be(),
+            len: (len as u16).to_be(),
+            checksum: 0,
+        },
+    );
+    let sum = match checksum(
+        &[&header, data],
+        pseudo_sum(NET_IP, to.addr, IP_PROTO_UDP, len),
+    ) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    header[mem::offset_of!(UdpHeader, checksum)..][0..2].copy_from_slice(&sum.to_be_bytes());
+    ip_send(to.addr, IP_PROTO_UDP, &[&header, data])?;
+    Ok(data.len())
+}
+
+// データグラムが届くまで待つ。`buf` に入りきらない部分は捨てる
+pub fn net_recvfrom(fd: usize, buf: &mut [u8]) -> Result<(usize, SockAddr), NetError> {
+    let sock = socket(fd)?;
+    let queue_len = ptr::addr_of!(sock.queue_len);
+    net_wait(|| unsafe { ptr::read_volatile(queue_len) > 0 })?;
+
+    let dgram = &sock.queue[sock.queue_head];
+    let len = core::cmp::min(dgram.len, buf.len());
+    buf[0..len].copy_from_slice(&dgram.data[0..len]);
+    let from = dgram.from;
+    sock.queue_head = (sock.queue_head + 1) % UDP_QUEUE_LEN;
+    sock.queue_len -= 1;
+    Ok((len, from))
+}
+
+pub fn net_listen(fd: usize, backlog: usize) -> Result<(), NetError> {
+    let sock = stream_socket(fd)?;
+    if sock.port == 0 || sock.tcb.is_some() {
+        return Err(NetError::BadSocket);
+    }
+    sock.tcb = Some(tcp_listen(sock.port, backlog)?);
+    Ok(())
+}
+
+// 接続が確立するまで待ち、その接続を表す新しいソケットを返す
+pub fn net_accept(fd: usize) -> Result<(usize, SockAddr), NetError> {
+    let sock = stream_socket(fd)?;
+    let listener = match sock.tcb {
+        Some(tcb) if tcp_state(tcb) == TcpState::Listen => tcb,
+        _ => return Err(NetError::BadSocket),
+    };
+
+    // accept した接続を入れるソケットを先に確保しておく
+    let new_fd = alloc_socket(SOCK_STREAM)?;
+    let mut accepted = None;
+    let waited = net_wait(|| {
+        accepted = tcp_accept(listener);
+        accepted.is_some()
+    });
+    if let Err(err) = waited {
+        net_close(new_fd)?;
+        return Err(err);
+    }
+    let (tcb, remote) = accepted.unwrap();
+    socket(new_fd)?.tcb = Some(tcb);
+    Ok((new_fd, remote))
+}
+
+pub fn net_connect(fd: usize, addr: &SockAddr) -> Result<(), NetError> {
+    let sock = stream_socket(fd)?;
+    if sock.tcb.is_some() {
+        return Err(NetError::BadSocket);
+    }
+    if sock.port == 0 {
+        sock.port = alloc_ephemeral_port();
+    }
+
+    let tcb = tcp_connect(sock.port, *addr)?;
+    sock.tcb = Some(tcb);
+    net_wait(|| tcp_state(tcb) != TcpState::SynSent)?;
+    match tcp_state(tcb) {
+        TcpState::Established | TcpState::CloseWait => Ok(()),
+        _ => Err(NetError::Refused),
+    }
+}
+
+// すべて送信バッファに入るまで待つ
+pub fn net_send(fd: usize, data: &[u8]) -> Result<usize, NetError> {
+    let tcb = stream_socket(fd)?.tcb.ok_or(NetError::NotConnected)?;
+    let mut sent = 0;
+    while sent < data.len() {
+        // 途中でシグナルが届いたら、そこまでに送った長さを返す
+        match net_wait(|| tcp_send_space(tcb) > 0 || tcp_state(tcb) == TcpState::Closed) {
+            Err(NetError::Interrupted) if sent > 0 => break,
+            result => result?,
+        }
+        sent += tcp_send(tcb, &data[sent..])?;
+    }
+    Ok(sent)
+}
+
+// データが届くまで待つ。相手が接続を閉じていれば 0 を返す
+pub fn net_recv(fd: usize, buf: &mut [u8]) -> Result<usize, NetError> {
+    let tcb = stream_socket(fd)?.tcb.ok_or(NetError::NotConnected)?;
+    net_wait(|| tcp_readable(tcb))?;
+    tcp_recv(tcb, buf)
+}
+
+pub fn net_close(fd: usize) -> Result<(), NetError> {
+    let sock = socket(fd)?;
+    if let Some(tcb) = sock.tcb {
+        tcp_close(tcb);
+    }
+    sock.in_use = false;
+    sock.port = 0;
+    sock.tcb = None;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn stream_socket_listens_and_closes() {
+        let fd = net_socket(SOCK_STREAM).unwrap();
+        assert_eq!(stream_socket(fd).unwrap().type_, SOCK_STREAM);
+        // bind していなければ listen できない
+        assert_eq!(net_listen(fd, 1), Err(NetError::BadSocket));
+
+        let addr = SockAddr {
+            addr: [0; 4],
+            port: 2323,
+        };
+        net_bind(fd, &addr).unwrap();
+        net_listen(fd, 1).unwrap();
+        assert!(port_in_use(2323));
+
+        net_close(fd).unwrap();
+        assert_eq!(socket(fd).err(), Some(NetError::BadSocket));
+        assert!(!port_in_use(2323));
+    }
+
+    #[test_case]
+    fn unknown_socket_type_is_unsupported() {
+        assert_eq!(net_socket(3), Err(NetError::Unsupported));
+    }
+}
@@ -0,0 +1,301 @@
+use crate::virtio_9p::{virtio_9p, VIRTIO_9P_MSIZE};
+
+// 9P2000.L のメッセージの種類 (R メッセージは T メッセージ + 1)
+const P9_RLERROR: u8 = 7;
+const P9_TLOPEN: u8 = 12;
+const P9_TVERSION: u8 = 100;
+const P9_TATTACH: u8 = 104;
+const P9_TWALK: u8 = 110;
+const P9_TREAD: u8 = 116;
+const P9_TWRITE: u8 = 118;
+const P9_TCLUNK: u8 = 120;
+
+const P9_VERSION: &str = "9P2000.L";
+const P9_NOTAG: u16 = 0xffff;
+const P9_NOFID: u32 = 0xffff_ffff;
+const P9_TAG: u16 = 1;
+// 1 回の Twalk で辿れるパス要素の最大数
+const P9_MAXWELEM: usize = 16;
+// size[4] type[1] tag[2] fid[4] offset[8] count[4]
+const P9_IOHDR_SIZE: usize = 23;
+const P9_ROOT_FID: u32 = 0;
+const P9_FID_MAX: usize = 32;
+
+// Linux の open(2) のフラグ
+pub const P9_O_RDONLY: u32 = 0;
+pub const P9_O_WRONLY: u32 = 1;
+pub const P9_O_TRUNC: u32 = 0o1000;
+const P9_ENOENT: u32 = 2;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum P9Error {
+    NoDevice,
+    Protocol,
+    NoFids,
+    // サーバーが Rlerror で返した errno
+    Errno(u32),
+}
+
+struct P9Client {
+    attached: bool,
+    msize: usize,
+    // 使用中の fid のビットマップ
+    fids: u32,
+}
+
+static mut P9: P9Client = P9Client {
+    attached: false,
+    msize: VIRTIO_9P_MSIZE,
+    fids: 0,
+};
+static mut P9_TX: [u8; VIRTIO_9P_MSIZE] = [0; VIRTIO_9P_MSIZE];
+static mut P9_RX: [u8; VIRTIO_9P_MSIZE] = [0; VIRTIO_9P_MSIZE];
+
+// T メッセージを組み立てる。先頭の size は send のときに埋める
+struct Writer {
+    buf: &'static mut [u8],
+    len: usize,
+}
+
+impl Writer {
+    fn new(type_: u8, tag: u16) -> Self {
+        let mut w = Writer {
+            buf: unsafe { &mut P9_TX },
+            len: 4,
+        };
+        w.u8(type_);
+        w.u16(tag);
+        w
+    }
+
+    fn bytes(&mut self, data: &[u8]) {
+        self.buf[self.len..(self.len + data.len())].copy_from_slice(data);
+        self.len += data.len();
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.bytes(&[v]);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u16(s.len() as u16);
+        self.bytes(s.as_bytes());
+    }
+}
+
+// R メッセージを先頭から読む
+struct Reader {
+    buf: &'static [u8],
+    pos: usize,
+}
+
+impl Reader {
+    fn bytes(&mut self, len: usize) -> Result<&'static [u8], P9Error> {
+        if self.pos + len > self.buf.len() {
+            return Err(P9Error::Protocol);
+        }
+        let data = &self.buf[self.pos..(self.pos + len)];
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn u16(&mut self) -> Result<u16, P9Error> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, P9Error> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'static str, P9Error> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.bytes(len)?).map_err(|_| P9Error::Protocol)
+    }
+
+    fn skip_qid(&mut self) -> Result<(), P9Error> {
+        self.bytes(13).map(|_| ())
+    }
+}
+
+// 要求を送り、応答の本体 (size, type, tag の後ろ) を返す
+fn rpc(w: Writer) -> Result<Reader, P9Error> {
+    let dev = virtio_9p().ok_or(P9Error::NoDevice)?;
+    let Writer { buf, len } = w;
+    let type_ = buf[4];
+    buf[0..4].copy_from_slice(&(len as u32).to_le_bytes());
+
+    let rx = unsafe { &mut P9_RX };
+    let rx_len = dev.request(&buf[0..len], rx);
+    if rx_len < 7 {
+        return Err(P9Error::Protocol);
+    }
+    let size = (u32::from_le_bytes(rx[0..4].try_into().unwrap()) as usize).min(rx_len);
+    let mut r = Reader {
+        buf: &rx[0..size],
+        pos: 7,
+    };
+    match rx[4] {
+        P9_RLERROR => Err(P9Error::Errno(r.u32()?)),
+        t if t == type_ + 1 => Ok(r),
+        _ => Err(P9Error::Protocol),
+    }
+}
+
+fn fid_alloc() -> Result<u32, P9Error> {
+    let client = unsafe { &mut P9 };
+    // fid 0 はルート用
+    let fid = (1..P9_FID_MAX)
+        .find(|&i| client.fids & (1 << i) == 0)
+        .ok_or(P9Error::NoFids)?;
+    client.fids |= 1 << fid;
+    Ok(fid as u32)
+}
+
+fn fid_free(fid: u32) {
+    unsafe { P9.fids &= !(1 << fid) };
+}
+
+// バージョンを合わせ、ホストの共有ディレクトリのルートを fid 0 に結びつける
+pub fn p9_attach() -> Result<(), P9Error> {
+    let client = unsafe { &mut P9 };
+    if client.attached {
+        return Ok(());
+    }
+
+    let mut w = Writer::new(P9_TVERSION, P9_NOTAG);
+    w.u32(VIRTIO_9P_MSIZE as u32);
+    w.str(P9_VERSION);
+    let mut r = rpc(w)?;
+    let msize = r.u32()? as usize;
+    if r.str()? != P9_VERSION {
+        return Err(P9Error::Protocol);
+    }
+    client.msize = msize.min(VIRTIO_9P_MSIZE);
+
+    let mut w = Writer::new(P9_TATTACH, P9_TAG);
+    w.u32(P9_ROOT_FID);
+    w.u32(P9_NOFID);
+    w.str("root");
+    w.str("");
+    w.u32(0);
+    rpc(w)?;
+
+    client.fids = 1 << P9_ROOT_FID;
+    client.attached = true;
+    Ok(())
+}
+
+// ルートから `path` を辿り、その先を指す新しい fid を返す
+pub fn p9_walk(path: &str) -> Result<u32, P9Error> {
+    let fid = fid_alloc()?;
+    let mut names = path.split('/').filter(|s| !s.is_empty() && *s != ".");
+    let mut from = P9_ROOT_FID;
+    loop {
+        let mut w = Writer::new(P9_TWALK, P9_TAG);
+        w.u32(from);
+        w.u32(fid);
+        let nwname_pos = w.len;
+        w.u16(0);
+        let mut nwname = 0;
+        for name in names.by_ref().take(P9_MAXWELEM) {
+            w.str(name);
+            nwname += 1;
+        }
+        w.buf[nwname_pos..(nwname_pos + 2)].copy_from_slice(&(nwname as u16).to_le_bytes());
+
+        // 途中で見つからなければ Rlerror ではなく短い Rwalk が返る
+        let result = rpc(w).and_then(|mut r| match r.u16()? {
+            n if n as usize == nwname => Ok(()),
+            _ => Err(P9Error::Errno(P9_ENOENT)),
+        });
+        if let Err(err) = result {
+            if from == fid {
+                let _ = p9_clunk(fid);
+            } else {
+                fid_free(fid);
+            }
+            return Err(err);
+        }
+        if nwname < P9_MAXWELEM {
+            return Ok(fid);
+        }
+        from = fid;
+    }
+}
+
+// fid を開き、1 回の読み書きで転送できる最大バイト数を返す
+pub fn p9_open(fid: u32, flags: u32) -> Result<usize, P9Error> {
+    let mut w = Writer::new(P9_TLOPEN, P9_TAG);
+    w.u32(fid);
+    w.u32(flags);
+    let mut r = rpc(w)?;
+    r.skip_qid()?;
+    Ok(io_size(r.u32()?))
+}
+
+fn io_size(iounit: u32) -> usize {
+    let max = unsafe { P9.msize } - P9_IOHDR_SIZE;
+    match iounit as usize {
+        0 => max,
+        n => n.min(max),
+    }
+}
+
+// ファイルの終わりに達すると `buf` より少ないバイト数を返す
+pub fn p9_read(fid: u32, iounit: usize, buf: &mut [u8]) -> Result<usize, P9Error> {
+    let mut off = 0;
+    while off < buf.len() {
+        let mut w = Writer::new(P9_TREAD, P9_TAG);
+        w.u32(fid);
+        w.u64(off as u64);
+        w.u32((buf.len() - off).min(iounit) as u32);
+        let mut r = rpc(w)?;
+        let count = r.u32()? as usize;
+        let data = r.bytes(count)?;
+        if count == 0 {
+            break;
+        }
+        buf[off..(off + count)].copy_from_slice(data);
+        off += count;
+    }
+    Ok(off)
+}
+
+pub fn p9_write(fid: u32, iounit: usize, data: &[u8]) -> Result<usize, P9Error> {
+    let mut off = 0;
+    while off < data.len() {
+        let len = (data.len() - off).min(iounit);
+        let mut w = Writer::new(P9_TWRITE, P9_TAG);
+        w.u32(fid);
+        w.u64(off as u64);
+        w.u32(len as u32);
+        w.bytes(&data[off..(off + len)]);
+        let count = rpc(w)?.u32()? as usize;
+        if count == 0 {
+            return Err(P9Error::Protocol);
+        }
+        off += count;
+    }
+    Ok(off)
+}
+
+pub fn p9_clunk(fid: u32) -> Result<(), P9Error> {
+    let mut w = Writer::new(P9_TCLUNK, P9_TAG);
+    w.u32(fid);
+    let result = rpc(w).map(|_| ());
+    // 失敗しても fid は解放される
+    fid_free(fid);
+    result
+}
@@ -0,0 +1,66 @@
+use core::ptr::{read_volatile, write_volatile};
+
+const PLIC_PRIORITY: usize = 0x0000;
+const PLIC_ENABLE: usize = 0x2000;
+const PLIC_ENABLE_STRIDE: usize = 0x80;
+const PLIC_THRESHOLD: usize = 0x20_0000;
+const PLIC_CLAIM: usize = 0x20_0004;
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+
+// デバイスの割り込みはすべて起動した hart の S モードのコンテキストで受ける
+static mut PLIC_CONTEXT: usize = 1;
+
+// PLIC のベースアドレスはデバイスツリーから得る
+fn plic_paddr() -> usize {
+    unsafe { crate::PLATFORM.plic.base }
+}
+
+fn plic_senable() -> usize {
+    PLIC_ENABLE + unsafe { PLIC_CONTEXT } * PLIC_ENABLE_STRIDE
+}
+
+fn plic_sthreshold() -> usize {
+    PLIC_THRESHOLD + unsafe { PLIC_CONTEXT } * PLIC_CONTEXT_STRIDE
+}
+
+fn plic_sclaim() -> usize {
+    PLIC_CLAIM + unsafe { PLIC_CONTEXT } * PLIC_CONTEXT_STRIDE
+}
+
+// カーネルが使う優先度、有効化、claim のレジスタを含むページ
+pub fn plic_mmio_pages() -> [usize; 3] {
+    [
+        plic_paddr() + PLIC_PRIORITY,
+        (plic_paddr() + plic_senable()) & !0xfff,
+        plic_paddr() + plic_sthreshold(),
+    ]
+}
+
+fn plic_read32(offset: usize) -> u32 {
+    unsafe { read_volatile((plic_paddr() + offset) as *const u32) }
+}
+
+fn plic_write32(offset: usize, value: u32) {
+    unsafe { write_volatile((plic_paddr() + offset) as *mut u32, value) }
+}
+
+// QEMU virt では hart N の S モードはコンテキスト 2N + 1
+pub fn plic_init(hartid: usize) {
+    unsafe { PLIC_CONTEXT = 2 * hartid + 1 };
+    plic_write32(plic_sthreshold(), 0);
+}
+
+pub fn plic_enable(irq: u32) {
+    plic_write32(PLIC_PRIORITY + irq as usize * 4, 1);
+    let offset = plic_senable() + (irq as usize / 32) * 4;
+    plic_write32(offset, plic_read32(offset) | (1 << (irq % 32)));
+}
+
+// 保留中でもっとも優先度の高い割り込みを返す。なければ 0
+pub fn plic_claim() -> u32 {
+    plic_read32(plic_sclaim())
+}
+
+pub fn plic_complete(irq: u32) {
+    plic_write32(plic_sclaim(), irq);
+}
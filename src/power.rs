@@ -0,0 +1,51 @@
+use core::{arch::asm, ptr::write_volatile};
+
+use common::println;
+
+use crate::{
+    sbi::{
+        sbi_system_reset, SBI_RESET_REASON_NONE, SBI_RESET_REASON_SYSTEM_FAILURE,
+        SBI_RESET_TYPE_COLD_REBOOT, SBI_RESET_TYPE_SHUTDOWN,
+    },
+    PLATFORM,
+};
+
+// sifive_test に書き込む値。FAIL は上位 16 ビットが QEMU の終了コードになる
+const TEST_FINISHER_FAIL: u32 = 0x3333;
+const TEST_FINISHER_PASS: u32 = 0x5555;
+const TEST_FINISHER_RESET: u32 = 0x7777;
+
+fn test_finisher(value: u32) {
+    if let Some(test) = unsafe { PLATFORM.test } {
+        unsafe { write_volatile(test.base as *mut u32, value) };
+    }
+}
+
+// どの方法でも止められなかったときは、割り込みも受けずに眠り続ける
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("wfi") };
+    }
+}
+
+// 電源を切る。`code` が 0 でなければ失敗として QEMU をその終了コードで終わらせる。
+// SRST は終了コードを渡せないので、失敗のときは sifive_test を先に使う
+pub fn power_off(code: u32) -> ! {
+    if code == 0 {
+        let err = sbi_system_reset(SBI_RESET_TYPE_SHUTDOWN, SBI_RESET_REASON_NONE);
+        println!("power: sbi shutdown failed: {err:?}");
+        test_finisher(TEST_FINISHER_PASS);
+    } else {
+        test_finisher(TEST_FINISHER_FAIL | (code << 16));
+        let err = sbi_system_reset(SBI_RESET_TYPE_SHUTDOWN, SBI_RESET_REASON_SYSTEM_FAILURE);
+        println!("power: sbi shutdown failed: {err:?}");
+    }
+    halt();
+}
+
+pub fn reboot() -> ! {
+    let err = sbi_system_reset(SBI_RESET_TYPE_COLD_REBOOT, SBI_RESET_REASON_NONE);
+    println!("power: sbi reboot failed: {err:?}");
+    test_finisher(TEST_FINISHER_RESET);
+    halt();
+}
@@ -1,24 +1,35 @@
 use core::{arch::asm, ptr};
 
-use common::{println, PAddr, VAddr, PAGE_SIZE, VIRTIO_BLK_PADDR};
+use common::{
+    println, PAddr, TrapFrame, VAddr, NSIG, PAGE_SIZE, SIGCHLD, SIGKILL, SIG_DFL, SIG_IGN,
+};
 
-use crate::memory::{alloc_pages, map_page, PAGE_R, PAGE_U, PAGE_W, PAGE_X, SATP_SV32};
+use crate::{
+    memory::{alloc_pages, map_page, PAGE_R, PAGE_U, PAGE_W, PAGE_X, SATP_SV32},
+    plic::plic_mmio_pages,
+    smp::{hart_id, kernel_unlock, smp_wake_harts, HARTS_MAX},
+    timer::timer_arm,
+    PLATFORM,
+};
 
 extern "C" {
     static mut __kernel_base: u32;
-    static mut __free_ram_end: u32;
 }
 
-const PROCS_MAX: usize = 8;
+// 先頭の HARTS_MAX 個は hart ごとのアイドルプロセス
+const PROCS_MAX: usize = HARTS_MAX + 8;
 const SSTATUS_SPIE: u32 = 1 << 5;
 const SSTATUS_SUM: u32 = 1 << 18;
 const SSTATUS: u32 = SSTATUS_SPIE | SSTATUS_SUM;
 const USER_BASE: usize = 0x01000000;
+// シグナルハンドラを呼ぶときに、割り込まれた関数のスタックを壊さないよう空けておく量
+const SIGNAL_STACK_GAP: u32 = 128;
 
 #[naked]
 extern "C" fn user_entry() {
     unsafe {
         asm!(
+            "call {unlock}",
             "la a0, {sepc}",
             "csrw sepc, a0",
             "la a0, {sstatus}",
@@ -26,17 +37,61 @@ extern "C" fn user_entry() {
             "sret",
             sepc = const USER_BASE,
             sstatus = const SSTATUS,
+            unlock = sym kernel_unlock,
             options(noreturn)
         );
     }
 }
 
+// ユーザーから渡された `addr` からの `size` バイトが、ユーザー空間 (カーネルより下) に収まっているか
+pub fn is_user_range(addr: u32, size: usize) -> bool {
+    let kernel_base = ptr::addr_of!(__kernel_base) as usize;
+    let addr = addr as usize;
+    addr >= USER_BASE && addr.checked_add(size).is_some_and(|end| end <= kernel_base)
+}
+
+// カーネルの領域とデバイスの MMIO をストレートマップする
+unsafe fn map_kernel_pages(page_table: PAddr) {
+    let mut paddr = ptr::addr_of_mut!(__kernel_base) as *mut u8;
+    while (paddr as usize) < PLATFORM.ram_end() {
+        map_page(
+            page_table,
+            paddr as u32,
+            paddr as u32,
+            PAGE_R | PAGE_W | PAGE_X,
+        );
+        paddr = paddr.add(PAGE_SIZE as usize);
+    }
+
+    for region in PLATFORM.virtio[0..PLATFORM.virtio_count].iter() {
+        let mut paddr = region.base & !(PAGE_SIZE - 1);
+        while paddr < region.base + region.size {
+            map_page(page_table, paddr as u32, paddr as u32, PAGE_R | PAGE_W);
+            paddr += PAGE_SIZE;
+        }
+    }
+
+    for paddr in plic_mmio_pages() {
+        map_page(page_table, paddr as u32, paddr as u32, PAGE_R | PAGE_W);
+    }
+
+    for region in [PLATFORM.uart, PLATFORM.rtc, PLATFORM.test]
+        .into_iter()
+        .flatten()
+    {
+        let paddr = region.base & !(PAGE_SIZE - 1);
+        map_page(page_table, paddr as u32, paddr as u32, PAGE_R | PAGE_W);
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 enum State {
     UNUSED,
     RUNNABLE,
     IDLE,
     EXITED,
+    // wakeup されるか、起きる時刻になるまで実行しない
+    BLOCKED,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -45,6 +100,18 @@ struct Process {
     state: State,
     sp: VAddr,
     page_table: PAddr,
+    // BLOCKED のときに待っているチャネル。0 はどのチャネルも待たない
+    wait_channel: usize,
+    // BLOCKED のときに起きる時刻 (time CSR の値)
+    wakeup_at: Option<u64>,
+    // 届いているシグナルのビットマスク
+    sig_pending: u32,
+    // シグナルごとのハンドラのアドレス (SIG_DFL, SIG_IGN も使える)
+    sig_handlers: [u32; NSIG],
+    // ハンドラから戻るときに sigreturn を呼ぶユーザー側のコード
+    sig_restorer: u32,
+    // ハンドラを実行中なら、割り込まれたときのレジスタと pc
+    sig_saved: Option<(TrapFrame, u32)>,
     stack: [u8; 8192],
 }
 
@@ -55,32 +122,71 @@ impl Process {
             state: State::UNUSED,
             sp: 0,
             page_table: 0,
+            wait_channel: 0,
+            wakeup_at: None,
+            sig_pending: 0,
+            sig_handlers: [SIG_DFL; NSIG],
+            sig_restorer: 0,
+            sig_saved: None,
             stack: [0; 8192],
         }
     }
+
+    // カーネルスタックの一番上の 1 ワードには、このプロセスを実行している hart の ID を置く。
+    // sscratch はここを指していて、kernel_entry はここから tp を読み直す
+    fn kernel_stack_top(&mut self) -> *mut u32 {
+        let stack = ptr::addr_of_mut!(self.stack) as *mut u8;
+        unsafe { (stack.add(self.stack.len()) as *mut u32).sub(1) }
+    }
+
+    fn unblock(&mut self) {
+        self.state = State::RUNNABLE;
+        self.wait_channel = 0;
+        self.wakeup_at = None;
+    }
+}
+
+// ハンドラが登録されていないときに無視するシグナル。それ以外はプロセスを終了させる
+fn signal_ignored_by_default(sig: u32) -> bool {
+    sig == SIGCHLD
 }
 
 pub struct ProcessManager {
     procs: [Process; PROCS_MAX],
-    pub current: usize,
+    // hart ごとに実行中のプロセス。hart N のアイドルプロセスは procs[N]
+    current: [usize; HARTS_MAX],
 }
 
 impl ProcessManager {
     pub const fn new() -> Self {
         let mut pm = Self {
             procs: [Process::new(); PROCS_MAX],
-            current: 0,
+            current: [0; HARTS_MAX],
         };
-        pm.procs[0].state = State::IDLE;
+        let mut hart = 0;
+        while hart < HARTS_MAX {
+            pm.procs[hart].state = State::IDLE;
+            pm.current[hart] = hart;
+            hart += 1;
+        }
         pm
     }
 
+    fn current(&self) -> usize {
+        self.current[hart_id()]
+    }
+
     pub fn init(&mut self) {
-        let proc = &mut self.procs[0];
+        for hart in 0..HARTS_MAX {
+            self.init_idle(hart);
+        }
+    }
+
+    fn init_idle(&mut self, hart: usize) {
+        let proc = &mut self.procs[hart];
 
         unsafe {
-            let stack = ptr::addr_of_mut!(proc.stack) as *mut u32;
-            let sp = stack.add(proc.stack.len());
+            let sp = proc.kernel_stack_top();
             *sp.offset(-1) = 0; // s11
             *sp.offset(-2) = 0; // s10
             *sp.offset(-3) = 0; // s9
@@ -96,16 +202,7 @@ impl ProcessManager {
             *sp.offset(-13) = 0; // ra
                                  //
             let page_table = alloc_pages(1);
-            let mut paddr = ptr::addr_of_mut!(__kernel_base) as *mut u8;
-            while paddr < ptr::addr_of_mut!(__free_ram_end) as *mut u8 {
-                map_page(
-                    page_table,
-                    paddr as u32,
-                    paddr as u32,
-                    PAGE_R | PAGE_W | PAGE_X,
-                );
-                paddr = paddr.add(PAGE_SIZE as usize);
-            }
+            map_kernel_pages(page_table);
 
             proc.pid = u32::MAX as u32;
             proc.state = State::IDLE;
@@ -114,7 +211,7 @@ impl ProcessManager {
         }
     }
 
-    pub fn create(&mut self, image: *const u32, image_size: usize) {
+    pub fn create(&mut self, image: *const u32, image_size: usize) -> u32 {
         unsafe {
             if let Some((i, proc)) = self
                 .procs
@@ -122,8 +219,7 @@ impl ProcessManager {
                 .enumerate()
                 .find(|(_, p)| p.state == State::UNUSED)
             {
-                let stack = ptr::addr_of_mut!(proc.stack) as *mut u32;
-                let sp = stack.add(proc.stack.len());
+                let sp = proc.kernel_stack_top();
                 *sp.offset(-1) = 0; // s11
                 *sp.offset(-2) = 0; // s10
                 *sp.offset(-3) = 0; // s9
@@ -139,23 +235,7 @@ impl ProcessManager {
                 *sp.offset(-13) = user_entry as u32; // ra
 
                 let page_table = alloc_pages(1);
-                let mut paddr = ptr::addr_of_mut!(__kernel_base) as *mut u8;
-                while paddr < ptr::addr_of_mut!(__free_ram_end) as *mut u8 {
-                    map_page(
-                        page_table,
-                        paddr as u32,
-                        paddr as u32,
-                        PAGE_R | PAGE_W | PAGE_X,
-                    );
-                    paddr = paddr.add(PAGE_SIZE as usize);
-                }
-
-                map_page(
-                    page_table,
-                    VIRTIO_BLK_PADDR as u32,
-                    VIRTIO_BLK_PADDR as u32,
-                    PAGE_R | PAGE_W,
-                );
+                map_kernel_pages(page_table);
 
                 let mut off = 0;
                 let pimage = image;
@@ -175,31 +255,46 @@ impl ProcessManager {
                 proc.state = State::RUNNABLE;
                 proc.sp = sp.offset(-13) as VAddr;
                 proc.page_table = page_table;
+                proc.wait_channel = 0;
+                proc.wakeup_at = None;
+                proc.sig_pending = 0;
+                proc.sig_handlers = [SIG_DFL; NSIG];
+                proc.sig_restorer = 0;
+                proc.sig_saved = None;
+                proc.pid
             } else {
                 panic!("no free process slots");
             }
         }
     }
 
-    pub fn yield_(&mut self) {
-        let mut next: usize = 0;
+    // `hart` で次に実行するプロセスを、いま実行中のものの次から順に探す。
+    // 動けるプロセスがなければその hart のアイドルプロセスを返す
+    fn pick_next(&self, hart: usize) -> usize {
+        let current = self.current[hart];
         for i in 0..PROCS_MAX {
-            let idx = (self.current + i + 1) % PROCS_MAX;
-            let proc = &self.procs[idx];
-            if proc.state == State::RUNNABLE {
-                next = idx;
-                break;
+            let idx = (current + i + 1) % PROCS_MAX;
+            // 他の hart で実行中のプロセスは選ばない
+            let elsewhere = idx != current && self.current.contains(&idx);
+            if self.procs[idx].state == State::RUNNABLE && !elsewhere {
+                return idx;
             }
         }
+        hart
+    }
 
-        if next == self.current {
+    pub fn yield_(&mut self) {
+        let hart = hart_id();
+        let current = self.current[hart];
+        let next = self.pick_next(hart);
+        if next == current {
             return;
         }
 
         unsafe {
             let next_proc = &mut self.procs[next];
-            let next_stack = ptr::addr_of_mut!(next_proc.stack) as *mut u32;
-            let next_stack_top = next_stack.add(next_proc.stack.len());
+            let next_stack_top = next_proc.kernel_stack_top();
+            *next_stack_top = hart as u32;
             asm!(
                 "sfence.vma",
                 "csrw satp, {satp}",
@@ -210,16 +305,181 @@ impl ProcessManager {
             );
         }
 
-        let prev = self.current;
-        self.current = next;
+        let prev = current;
+        self.current[hart] = next;
         switch_context(&mut self.procs[prev].sp, &self.procs[next].sp);
     }
 
     pub fn exit(&mut self) {
-        println!("process {} exited", self.current);
-        self.procs[self.current].state = State::EXITED;
+        println!("process {} exited", self.current());
+        let current = self.current();
+        self.procs[current].state = State::EXITED;
+        self.yield_();
+    }
+
+    // `chan` で wakeup されるか `deadline` を過ぎるまで眠る。起こされても条件が
+    // 成り立っているとは限らないので、呼び出し側で確かめ直すこと。
+    // アイドルプロセスは眠れないので、割り込みを 1 回待つだけにする
+    pub fn sleep(&mut self, chan: usize, deadline: Option<u64>) {
+        if let Some(deadline) = deadline {
+            timer_arm(deadline);
+        }
+        let current = self.current();
+        if current == hart_id() {
+            crate::wait_for_interrupt();
+            return;
+        }
+
+        let proc = &mut self.procs[current];
+        proc.state = State::BLOCKED;
+        proc.wait_channel = chan;
+        proc.wakeup_at = deadline;
         self.yield_();
     }
+
+    pub fn wakeup(&mut self, chan: usize) {
+        let mut woken = false;
+        for proc in self.procs.iter_mut() {
+            if proc.state == State::BLOCKED && proc.wait_channel == chan {
+                proc.unblock();
+                woken = true;
+            }
+        }
+        if woken {
+            self.wake_idle_harts();
+        }
+    }
+
+    // アイドルの hart を起こして、動けるようになったプロセスを拾わせる
+    fn wake_idle_harts(&self) {
+        let idle = (0..HARTS_MAX)
+            .filter(|&hart| self.current[hart] == hart)
+            .fold(0, |mask, hart| mask | 1 << hart);
+        smp_wake_harts(idle);
+    }
+
+    // 起きる時刻を過ぎたプロセスを起こし、次に起こす時刻を返す
+    pub fn wakeup_expired(&mut self, now: u64) -> Option<u64> {
+        let mut next: Option<u64> = None;
+        let mut woken = false;
+        for proc in self.procs.iter_mut() {
+            if proc.state != State::BLOCKED {
+                continue;
+            }
+            match proc.wakeup_at {
+                Some(deadline) if deadline <= now => {
+                    proc.unblock();
+                    woken = true;
+                }
+                Some(deadline) => next = Some(next.map_or(deadline, |n| n.min(deadline))),
+                None => {}
+            }
+        }
+        if woken {
+            self.wake_idle_harts();
+        }
+        next
+    }
+
+    // 終了していないユーザープロセスがあるか
+    pub fn has_processes(&self) -> bool {
+        self.procs
+            .iter()
+            .any(|p| p.state == State::RUNNABLE || p.state == State::BLOCKED)
+    }
+
+    pub fn current_pid(&self) -> u32 {
+        self.procs[self.current()].pid
+    }
+
+    // 実際に届けるのは、そのプロセスがユーザーモードに戻るとき。
+    // 眠っているプロセスは起こし、待っている処理を EINTR で終わらせる
+    pub fn kill(&mut self, pid: u32, sig: u32) -> Result<(), ()> {
+        if sig == 0 || sig as usize >= NSIG {
+            return Err(());
+        }
+        let proc = self
+            .procs
+            .iter_mut()
+            .find(|p| p.pid == pid && (p.state == State::RUNNABLE || p.state == State::BLOCKED))
+            .ok_or(())?;
+        proc.sig_pending |= 1 << sig;
+        if proc.state == State::BLOCKED {
+            proc.unblock();
+            self.wake_idle_harts();
+        }
+        Ok(())
+    }
+
+    // ハンドラを登録し、それまでのハンドラを返す。SIGKILL は変えられない
+    pub fn sigaction(&mut self, sig: u32, handler: u32, restorer: u32) -> Result<u32, ()> {
+        if sig == 0 || sig as usize >= NSIG || sig == SIGKILL {
+            return Err(());
+        }
+        let proc = &mut self.procs[self.current()];
+        let old = proc.sig_handlers[sig as usize];
+        proc.sig_handlers[sig as usize] = handler;
+        if handler != SIG_DFL && handler != SIG_IGN {
+            proc.sig_restorer = restorer;
+        }
+        Ok(old)
+    }
+
+    pub fn signal_pending(&self) -> bool {
+        self.procs[self.current()].sig_pending != 0
+    }
+
+    // ハンドラから戻ってきたので、割り込まれたところのレジスタと pc を戻す
+    pub fn sigreturn(&mut self, f: &mut TrapFrame) -> Option<u32> {
+        let (frame, pc) = self.procs[self.current()].sig_saved.take()?;
+        *f = frame;
+        Some(pc)
+    }
+
+    // ユーザーモードに戻る直前に呼ぶ。ハンドラがあれば、戻り先をハンドラに書き換える
+    pub fn deliver_signals(&mut self, f: &mut TrapFrame, pc: &mut u32) {
+        loop {
+            let proc = &mut self.procs[self.current()];
+            // ハンドラの実行中は、他のシグナルは sigreturn まで待たせる
+            let deliverable = if proc.sig_saved.is_some() {
+                proc.sig_pending & (1 << SIGKILL)
+            } else {
+                proc.sig_pending
+            };
+            if deliverable == 0 {
+                return;
+            }
+            let sig = deliverable.trailing_zeros();
+            proc.sig_pending &= !(1 << sig);
+
+            match proc.sig_handlers[sig as usize] {
+                SIG_IGN => continue,
+                SIG_DFL if signal_ignored_by_default(sig) => continue,
+                _ if sig == SIGKILL => {}
+                SIG_DFL => {}
+                handler => {
+                    proc.sig_saved = Some((*f, *pc));
+                    f.a0 = sig;
+                    f.ra = proc.sig_restorer;
+                    f.sp = (f.sp - SIGNAL_STACK_GAP) & !0xf;
+                    *pc = handler;
+                    return;
+                }
+            }
+
+            println!("process {} killed by signal {}", proc.pid, sig);
+            proc.state = State::EXITED;
+            self.yield_();
+        }
+    }
+}
+
+// `cond` が成り立つまで `chan` で眠る。割り込みハンドラが条件を変えたら wakeup(chan) で起こす。
+// カーネル内では割り込みが無効なので、確かめてから眠るまでの間に wakeup を取りこぼすことはない
+pub fn wait_event(chan: usize, cond: impl Fn() -> bool) {
+    while !cond() {
+        unsafe { crate::PM.sleep(chan, None) };
+    }
 }
 
 #[naked]
@@ -262,3 +522,154 @@ extern "C" fn switch_context(prev_sp: *mut u32, next_sp: *const u32) {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PM;
+    use common::SIGUSR1;
+
+    fn pm() -> &'static mut ProcessManager {
+        unsafe { &mut *ptr::addr_of_mut!(PM) }
+    }
+
+    // 空いているスロットを RUNNABLE にする。ユーザーモードには入らないので、
+    // yield_ で切り替えないテストにだけ使う
+    fn fake_process() -> usize {
+        let pm = pm();
+        let idx = pm
+            .procs
+            .iter()
+            .position(|p| p.state == State::UNUSED)
+            .unwrap();
+        let proc = &mut pm.procs[idx];
+        proc.pid = idx as u32;
+        proc.state = State::RUNNABLE;
+        proc.sig_pending = 0;
+        idx
+    }
+
+    fn release(idx: usize) {
+        let proc = &mut pm().procs[idx];
+        proc.unblock();
+        proc.state = State::UNUSED;
+        proc.sig_pending = 0;
+    }
+
+    fn block(idx: usize, chan: usize, deadline: Option<u64>) {
+        let proc = &mut pm().procs[idx];
+        proc.state = State::BLOCKED;
+        proc.wait_channel = chan;
+        proc.wakeup_at = deadline;
+    }
+
+    #[test_case]
+    fn pick_next_is_round_robin() {
+        let hart = hart_id();
+        let (a, b) = (fake_process(), fake_process());
+        let pm = pm();
+        assert_eq!(pm.pick_next(hart), a);
+        pm.current[hart] = a;
+        assert_eq!(pm.pick_next(hart), b);
+        pm.current[hart] = b;
+        assert_eq!(pm.pick_next(hart), a);
+        pm.current[hart] = hart;
+        release(a);
+        release(b);
+        assert_eq!(pm.pick_next(hart), hart);
+    }
+
+    #[test_case]
+    fn pick_next_skips_processes_on_other_harts() {
+        let hart = hart_id();
+        let other = (hart + 1) % HARTS_MAX;
+        let (a, b) = (fake_process(), fake_process());
+        let pm = pm();
+        pm.current[other] = a;
+        let next = pm.pick_next(hart);
+        pm.current[other] = other;
+        release(a);
+        release(b);
+        assert_eq!(next, b);
+    }
+
+    #[test_case]
+    fn wakeup_unblocks_only_its_channel() {
+        let hart = hart_id();
+        let a = fake_process();
+        let chan = ptr::addr_of!(PM) as usize;
+        block(a, chan, None);
+        let pm = pm();
+        assert_eq!(pm.pick_next(hart), hart);
+        pm.wakeup(chan + 1);
+        assert_eq!(pm.procs[a].state, State::BLOCKED);
+        pm.wakeup(chan);
+        assert_eq!(pm.procs[a].state, State::RUNNABLE);
+        assert_eq!(pm.pick_next(hart), a);
+        release(a);
+    }
+
+    #[test_case]
+    fn wakeup_expired_returns_next_deadline() {
+        let (a, b) = (fake_process(), fake_process());
+        block(a, 0, Some(100));
+        block(b, 0, Some(200));
+        let pm = pm();
+        assert_eq!(pm.wakeup_expired(150), Some(200));
+        assert_eq!(pm.procs[a].state, State::RUNNABLE);
+        assert_eq!(pm.procs[b].state, State::BLOCKED);
+        assert_eq!(pm.wakeup_expired(200), None);
+        assert_eq!(pm.procs[b].state, State::RUNNABLE);
+        release(a);
+        release(b);
+    }
+
+    #[test_case]
+    fn kill_wakes_blocked_process() {
+        let a = fake_process();
+        block(a, 0, None);
+        let pm = pm();
+        assert!(pm.kill(a as u32, SIGUSR1).is_ok());
+        assert_eq!(pm.procs[a].state, State::RUNNABLE);
+        assert_eq!(pm.procs[a].sig_pending, 1 << SIGUSR1);
+        assert!(pm.kill(a as u32, NSIG as u32).is_err());
+        release(a);
+        assert!(pm.kill(a as u32, SIGUSR1).is_err());
+    }
+
+    static mut TASK_RAN: bool = false;
+
+    // カーネルの中だけで動くプロセス。印を付けて終了する
+    extern "C" fn task_entry() {
+        unsafe { TASK_RAN = true };
+        let pm = pm();
+        let current = pm.current();
+        pm.procs[current].state = State::EXITED;
+        pm.yield_();
+        unreachable!();
+    }
+
+    #[test_case]
+    fn yield_switches_to_runnable_process() {
+        let hart = hart_id();
+        let idx = fake_process();
+        let pm = pm();
+        unsafe {
+            // init_idle と同じく、switch_context が task_entry に戻るようにスタックを作る
+            let proc = &mut pm.procs[idx];
+            let sp = proc.kernel_stack_top().offset(-13);
+            ptr::write_bytes(sp, 0, 13);
+            *sp = task_entry as u32; // ra
+            let page_table = alloc_pages(1);
+            map_kernel_pages(page_table);
+            proc.sp = sp as VAddr;
+            proc.page_table = page_table;
+
+            pm.yield_();
+            assert!(TASK_RAN);
+        }
+        assert_eq!(pm.current[hart], hart);
+        assert_eq!(pm.procs[idx].state, State::EXITED);
+        release(idx);
+    }
+}
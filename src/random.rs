@@ -0,0 +1,172 @@
+use common::read_csr;
+
+use crate::{println, virtio_rng::virtio_rng};
+
+// ChaCha20 を使った CSPRNG。出力するたびに鍵を作り直すので (fast key erasure)、
+// 後から内部状態が漏れても過去の出力は復元できない
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+const RANDOM_SEED_SIZE: usize = 32;
+// これだけ出力したら virtio-rng から再シードする
+const RANDOM_RESEED_INTERVAL: usize = 1024 * 1024;
+// readfile でこのパスを読むと乱数が返る
+pub const RANDOM_DEVICE_PATH: &str = "/dev/random";
+// デバイスがないときに集めるタイマーのゆらぎのサンプル数
+const RANDOM_JITTER_SAMPLES: usize = 4096;
+
+struct Csprng {
+    key: [u32; 8],
+    counter: u64,
+    seeded: bool,
+    since_reseed: usize,
+}
+
+static mut RANDOM: Csprng = Csprng {
+    key: [0; 8],
+    counter: 0,
+    seeded: false,
+    since_reseed: 0,
+};
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u32; 8], counter: u64) -> [u32; 16] {
+    let mut init = [0u32; 16];
+    init[0..4].copy_from_slice(&CHACHA_CONSTANTS);
+    init[4..12].copy_from_slice(key);
+    init[12] = counter as u32;
+    init[13] = (counter >> 32) as u32;
+
+    let mut s = init;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    for (x, y) in s.iter_mut().zip(init.iter()) {
+        *x = x.wrapping_add(*y);
+    }
+    s
+}
+
+impl Csprng {
+    fn next_block(&mut self) -> [u32; 16] {
+        let block = chacha20_block(&self.key, self.counter);
+        self.counter = self.counter.wrapping_add(1);
+        block
+    }
+
+    fn rekey(&mut self) {
+        let block = self.next_block();
+        self.key.copy_from_slice(&block[0..8]);
+    }
+
+    // 今の鍵にシードを混ぜてから鍵を作り直す
+    fn mix(&mut self, seed: &[u8]) {
+        for (i, chunk) in seed.chunks(4).enumerate() {
+            let mut word = [0u8; 4];
+            word[0..chunk.len()].copy_from_slice(chunk);
+            self.key[i % 8] ^= u32::from_le_bytes(word);
+            if i % 8 == 7 {
+                self.rekey();
+            }
+        }
+        self.rekey();
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(64) {
+            let block = self.next_block();
+            for (i, b) in chunk.iter_mut().enumerate() {
+                *b = (block[i / 4] >> (8 * (i % 4))) as u8;
+            }
+        }
+        self.rekey();
+        self.since_reseed += buf.len();
+    }
+}
+
+// virtio-rng があればそこからシードを読む
+fn device_seed(seed: &mut [u8; RANDOM_SEED_SIZE]) -> bool {
+    let Some(rng) = virtio_rng() else {
+        return false;
+    };
+    let mut filled = 0;
+    // 一度に全部返ってくるとは限らないので、何回か読む
+    for _ in 0..16 {
+        filled += rng.read(&mut seed[filled..]);
+        if filled == seed.len() {
+            return true;
+        }
+    }
+    false
+}
+
+// 時刻とサイクルカウンタの読み出し間隔のゆらぎを集める。
+// エミュレータ上では予測できてしまう可能性があるので、デバイスがないときの最後の手段
+fn jitter_seed(seed: &mut [u8; RANDOM_SEED_SIZE]) {
+    let mut acc = [0u32; 8];
+    let mut prev = read_csr!("cycle") as u32;
+    for i in 0..RANDOM_JITTER_SAMPLES {
+        let mut x = 0u32;
+        // 少しだけ処理を挟んで、かかった時間のばらつきを測る
+        for j in 0..(prev & 0xf) {
+            x = x.wrapping_mul(31).wrapping_add(j);
+        }
+        let now = (read_csr!("cycle") as u32) ^ (read_csr!("time") as u32).rotate_left(16);
+        let delta = now.wrapping_sub(prev) ^ x;
+        prev = now;
+        acc[i % 8] = acc[i % 8].rotate_left(5) ^ delta;
+    }
+    let block = chacha20_block(&acc, 0);
+    for (i, b) in seed.iter_mut().enumerate() {
+        *b = (block[i / 4] >> (8 * (i % 4))) as u8;
+    }
+}
+
+pub fn random_init() {
+    let mut seed = [0u8; RANDOM_SEED_SIZE];
+    let rng = unsafe { &mut RANDOM };
+    if device_seed(&mut seed) {
+        println!("random: seeded from virtio-rng");
+    } else {
+        jitter_seed(&mut seed);
+        println!("random: no entropy device, seeded from timer jitter");
+    }
+    rng.mix(&seed);
+    rng.seeded = true;
+}
+
+pub fn random_fill(buf: &mut [u8]) {
+    if unsafe { !RANDOM.seeded } {
+        random_init();
+    }
+    let rng = unsafe { &mut RANDOM };
+    if rng.since_reseed >= RANDOM_RESEED_INTERVAL {
+        let mut seed = [0u8; RANDOM_SEED_SIZE];
+        if device_seed(&mut seed) {
+            rng.mix(&seed);
+        }
+        rng.since_reseed = 0;
+    }
+    rng.fill(buf);
+}
+
+pub fn random_u32() -> u32 {
+    let mut buf = [0u8; 4];
+    random_fill(&mut buf);
+    u32::from_le_bytes(buf)
+}
@@ -0,0 +1,36 @@
+use crate::PLATFORM;
+use core::ptr::read_volatile;
+
+// QEMU virt の goldfish RTC。1970-01-01 (UTC) からのナノ秒を数えている
+const RTC_TIME_LOW: usize = 0x00;
+// TIME_LOW を読んだときの上位 32 ビットがラッチされているので、必ず LOW から読む
+const RTC_TIME_HIGH: usize = 0x04;
+
+pub struct Rtc {
+    base: usize,
+}
+
+impl Rtc {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    pub fn now_ns(&self) -> u64 {
+        let lo = self.read(RTC_TIME_LOW);
+        let hi = self.read(RTC_TIME_HIGH);
+        ((hi as u64) << 32) | lo as u64
+    }
+}
+
+static mut RTC: Option<Rtc> = None;
+
+pub fn rtc_init() {
+    let Some(region) = (unsafe { PLATFORM.rtc }) else {
+        return;
+    };
+    unsafe { RTC = Some(Rtc { base: region.base }) };
+}
+
+pub fn rtc() -> Option<&'static Rtc> {
+    unsafe { RTC.as_ref() }
+}
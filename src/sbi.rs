@@ -1,8 +1,74 @@
 use core::arch::asm;
 
+use common::println;
+
+// v0.1 の古い拡張。Base 拡張がないファームウェアではこれしか使えない
+const SBI_EXT_LEGACY_SET_TIMER: i32 = 0x00;
+const SBI_EXT_LEGACY_CONSOLE_PUTCHAR: i32 = 0x01;
+const SBI_EXT_LEGACY_CONSOLE_GETCHAR: i32 = 0x02;
+const SBI_EXT_BASE: i32 = 0x10;
+const SBI_EXT_TIME: i32 = 0x54494d45;
+const SBI_EXT_IPI: i32 = 0x735049;
+const SBI_EXT_HSM: i32 = 0x48534d;
+const SBI_EXT_SRST: i32 = 0x53525354;
+const SBI_EXT_DBCN: i32 = 0x4442434e;
+
+const SBI_BASE_GET_SPEC_VERSION: i32 = 0;
+const SBI_BASE_GET_IMPL_ID: i32 = 1;
+const SBI_BASE_GET_IMPL_VERSION: i32 = 2;
+const SBI_BASE_PROBE_EXTENSION: i32 = 3;
+const SBI_DBCN_CONSOLE_WRITE: i32 = 0;
+const SBI_DBCN_CONSOLE_READ: i32 = 1;
+const SBI_DBCN_CONSOLE_WRITE_BYTE: i32 = 2;
+
+pub const SBI_RESET_TYPE_SHUTDOWN: u32 = 0;
+pub const SBI_RESET_TYPE_COLD_REBOOT: u32 = 1;
+pub const SBI_RESET_REASON_NONE: u32 = 0;
+pub const SBI_RESET_REASON_SYSTEM_FAILURE: u32 = 1;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoShmem,
+    Unknown(i32),
+}
+
+impl SbiError {
+    fn from_code(code: i32) -> Self {
+        match code {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            -9 => SbiError::NoShmem,
+            code => SbiError::Unknown(code),
+        }
+    }
+}
+
 struct SbiRet {
-    _error: i32,
-    _value: i32,
+    error: i32,
+    value: i32,
+}
+
+impl SbiRet {
+    fn into_result(self) -> Result<i32, SbiError> {
+        match self.error {
+            0 => Ok(self.value),
+            code => Err(SbiError::from_code(code)),
+        }
+    }
 }
 
 unsafe fn sbi_call(
@@ -23,23 +89,234 @@ unsafe fn sbi_call(
         in("a2") arg2, in("a3") arg3, in("a4") arg4, in("a5") arg5,
         in("a6") fid, in("a7") eid
     );
-    SbiRet {
-        _error: error,
-        _value: value,
+    SbiRet { error, value }
+}
+
+fn sbi_call0(eid: i32, fid: i32) -> Result<i32, SbiError> {
+    unsafe { sbi_call(0, 0, 0, 0, 0, 0, fid, eid) }.into_result()
+}
+
+// Base 拡張で調べた、使える拡張
+#[derive(Copy, Clone, Debug)]
+struct SbiExtensions {
+    time: bool,
+    ipi: bool,
+    hsm: bool,
+    srst: bool,
+    dbcn: bool,
+}
+
+static mut SBI_EXTENSIONS: SbiExtensions = SbiExtensions {
+    time: false,
+    ipi: false,
+    hsm: false,
+    srst: false,
+    dbcn: false,
+};
+
+fn extensions() -> SbiExtensions {
+    unsafe { SBI_EXTENSIONS }
+}
+
+fn impl_name(id: i32) -> &'static str {
+    match id {
+        0 => "BBL",
+        1 => "OpenSBI",
+        2 => "Xvisor",
+        3 => "KVM",
+        4 => "RustSBI",
+        5 => "Diosix",
+        6 => "Coffer",
+        _ => "unknown",
+    }
+}
+
+fn probe(eid: i32) -> bool {
+    let ret = unsafe { sbi_call(eid, 0, 0, 0, 0, 0, SBI_BASE_PROBE_EXTENSION, SBI_EXT_BASE) };
+    matches!(ret.into_result(), Ok(value) if value != 0)
+}
+
+// ファームウェアのバージョンを表示し、使える拡張を調べておく。
+// Base 拡張がなければ v0.1 とみなして古い拡張だけを使う
+pub fn sbi_init() {
+    let Ok(version) = sbi_call0(SBI_EXT_BASE, SBI_BASE_GET_SPEC_VERSION) else {
+        println!("sbi: legacy v0.1 firmware");
+        return;
+    };
+    let impl_id = sbi_call0(SBI_EXT_BASE, SBI_BASE_GET_IMPL_ID).unwrap_or(-1);
+    let impl_version = sbi_call0(SBI_EXT_BASE, SBI_BASE_GET_IMPL_VERSION).unwrap_or(0);
+
+    let ext = SbiExtensions {
+        time: probe(SBI_EXT_TIME),
+        ipi: probe(SBI_EXT_IPI),
+        hsm: probe(SBI_EXT_HSM),
+        srst: probe(SBI_EXT_SRST),
+        dbcn: probe(SBI_EXT_DBCN),
+    };
+    unsafe { SBI_EXTENSIONS = ext };
+
+    println!(
+        "sbi: spec v{}.{}, {} {:x}, time={} ipi={} hsm={} srst={} dbcn={}",
+        (version >> 24) & 0x7f,
+        version & 0xff_ffff,
+        impl_name(impl_id),
+        impl_version,
+        ext.time,
+        ext.ipi,
+        ext.hsm,
+        ext.srst,
+        ext.dbcn,
+    );
+}
+
+pub fn sbi_putchar(ch: u8) {
+    unsafe {
+        if extensions().dbcn {
+            sbi_call(
+                ch as i32,
+                0,
+                0,
+                0,
+                0,
+                0,
+                SBI_DBCN_CONSOLE_WRITE_BYTE,
+                SBI_EXT_DBCN,
+            );
+        } else {
+            sbi_call(ch as i32, 0, 0, 0, 0, 0, 0, SBI_EXT_LEGACY_CONSOLE_PUTCHAR);
+        }
+    }
+}
+
+// 文字列をまとめて出力する。DBCN があれば 1 回の呼び出しで書き込む。
+// DBCN は物理アドレスのバッファから読むので、ストレートマップされたカーネルのバッファを渡す
+pub fn sbi_console_write(bytes: &[u8]) {
+    if !extensions().dbcn {
+        for &ch in bytes {
+            sbi_putchar(ch);
+        }
+        return;
+    }
+
+    // 一度にすべて書き込まれるとは限らないので、残りを書き込み直す
+    let mut bytes = bytes;
+    while !bytes.is_empty() {
+        let ret = unsafe {
+            sbi_call(
+                bytes.len() as i32,
+                bytes.as_ptr() as i32,
+                0,
+                0,
+                0,
+                0,
+                SBI_DBCN_CONSOLE_WRITE,
+                SBI_EXT_DBCN,
+            )
+        };
+        match ret.into_result() {
+            Ok(written) => bytes = &bytes[(written as usize).min(bytes.len())..],
+            Err(_) => return,
+        }
     }
 }
 
-#[no_mangle]
-pub fn putchar(ch: u8) {
+// DBCN は物理アドレスのバッファに読み込むので、ストレートマップされたカーネルの変数を使う
+static mut SBI_READ_BUF: u8 = 0;
+
+// 入力がなければ -1 を返す
+pub fn sbi_getchar() -> i32 {
     unsafe {
-        sbi_call(ch as i32, 0, 0, 0, 0, 0, 0, 1);
+        if !extensions().dbcn {
+            return sbi_call(0, 0, 0, 0, 0, 0, 0, SBI_EXT_LEGACY_CONSOLE_GETCHAR).error;
+        }
+        let buf = core::ptr::addr_of_mut!(SBI_READ_BUF);
+        let ret = sbi_call(
+            1,
+            buf as i32,
+            0,
+            0,
+            0,
+            0,
+            SBI_DBCN_CONSOLE_READ,
+            SBI_EXT_DBCN,
+        );
+        match ret.into_result() {
+            Ok(1) => *buf as i32,
+            _ => -1,
+        }
     }
 }
 
-#[no_mangle]
-pub fn getchar() -> i32 {
+// `stime_value` (time CSR の値) になったらタイマー割り込みを起こす。呼ぶと保留中の割り込みも消える
+pub fn sbi_set_timer(stime_value: u64) {
+    let eid = if extensions().time {
+        SBI_EXT_TIME
+    } else {
+        SBI_EXT_LEGACY_SET_TIMER
+    };
     unsafe {
-        let ret = sbi_call(0, 0, 0, 0, 0, 0, 0, 2);
-        return ret._error;
+        sbi_call(
+            stime_value as i32,
+            (stime_value >> 32) as i32,
+            0,
+            0,
+            0,
+            0,
+            0,
+            eid,
+        );
+    }
+}
+
+// 止まっている hart を S モードの `start_addr` から動かす。a0 に hart ID、a1 に `opaque` が入る
+pub fn sbi_hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), SbiError> {
+    if !extensions().hsm {
+        return Err(SbiError::NotSupported);
+    }
+    let ret = unsafe {
+        sbi_call(
+            hartid as i32,
+            start_addr as i32,
+            opaque as i32,
+            0,
+            0,
+            0,
+            0,
+            SBI_EXT_HSM,
+        )
+    };
+    ret.into_result().map(|_| ())
+}
+
+// `hart_mask` のビットが立っている hart にソフトウェア割り込みを送る
+pub fn sbi_send_ipi(hart_mask: usize) -> Result<(), SbiError> {
+    if !extensions().ipi {
+        return Err(SbiError::NotSupported);
+    }
+    unsafe { sbi_call(hart_mask as i32, 0, 0, 0, 0, 0, 0, SBI_EXT_IPI) }
+        .into_result()
+        .map(|_| ())
+}
+
+// 電源を切るか再起動する。成功すれば戻ってこないので、戻ってきたらその理由を返す
+pub fn sbi_system_reset(reset_type: u32, reason: u32) -> SbiError {
+    if !extensions().srst {
+        return SbiError::NotSupported;
+    }
+    let ret = unsafe {
+        sbi_call(
+            reset_type as i32,
+            reason as i32,
+            0,
+            0,
+            0,
+            0,
+            0,
+            SBI_EXT_SRST,
+        )
+    };
+    match ret.into_result() {
+        Ok(_) => SbiError::Failed,
+        Err(err) => err,
     }
 }
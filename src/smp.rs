@@ -0,0 +1,106 @@
+use core::{arch::asm, cell::UnsafeCell};
+
+use common::{println, PAGE_SIZE};
+
+use crate::{
+    memory::alloc_pages,
+    sbi::{sbi_hart_start, sbi_send_ipi},
+    PLATFORM,
+};
+
+pub const HARTS_MAX: usize = 4;
+const HART_STACK_PAGES: usize = 4;
+
+// カーネル内では tp に自分の hart ID を置いている
+pub fn hart_id() -> usize {
+    let id: usize;
+    unsafe { asm!("mv {}, tp", out(reg) id) };
+    id
+}
+
+// ターゲットは rv32i なので、amoswap の前後だけ A 拡張を有効にしてアセンブルする
+pub struct SpinLock {
+    locked: UnsafeCell<u32>,
+}
+
+unsafe impl Sync for SpinLock {}
+
+impl SpinLock {
+    pub const fn new() -> Self {
+        Self {
+            locked: UnsafeCell::new(0),
+        }
+    }
+
+    pub fn lock(&self) {
+        loop {
+            let old: u32;
+            unsafe {
+                asm!(
+                    ".option push",
+                    ".option arch, +a",
+                    "amoswap.w.aq {old}, {one}, ({lock})",
+                    ".option pop",
+                    old = out(reg) old,
+                    one = in(reg) 1u32,
+                    lock = in(reg) self.locked.get(),
+                );
+            }
+            if old == 0 {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn unlock(&self) {
+        unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +a",
+                "amoswap.w.rl zero, zero, ({lock})",
+                ".option pop",
+                lock = in(reg) self.locked.get(),
+            );
+        }
+    }
+}
+
+// カーネルのデータ構造はほとんどが static mut なので、カーネルを実行する hart は一度に 1 つだけにする。
+// ユーザーモードで動いている間と、wfi で割り込みを待っている間だけ手放す
+static KERNEL_LOCK: SpinLock = SpinLock::new();
+// 起動した hart のビットマスク
+static mut HARTS_ONLINE: u32 = 0;
+
+pub fn kernel_lock() {
+    KERNEL_LOCK.lock();
+}
+
+// 初めてユーザーモードに入るプロセスは user_entry から呼ぶ
+pub extern "C" fn kernel_unlock() {
+    KERNEL_LOCK.unlock();
+}
+
+// 残りの hart を SBI の HSM 拡張で起こす。それぞれ自分のスタックで `entry` から動き出す
+pub fn smp_start_harts(entry: usize) {
+    let me = hart_id();
+    unsafe { HARTS_ONLINE = 1 << me };
+    for hart in 0..unsafe { PLATFORM.hart_count }.min(HARTS_MAX) {
+        if hart == me {
+            continue;
+        }
+        let stack_top = alloc_pages(HART_STACK_PAGES) as usize + HART_STACK_PAGES * PAGE_SIZE;
+        match sbi_hart_start(hart, entry, stack_top) {
+            Ok(()) => unsafe { HARTS_ONLINE |= 1 << hart },
+            Err(err) => println!("smp: failed to start hart {hart}: {err:?}"),
+        }
+    }
+}
+
+// `harts` のうち起動している hart にソフトウェア割り込みを送り、wfi から起こす
+pub fn smp_wake_harts(harts: u32) {
+    let mask = harts & unsafe { HARTS_ONLINE } & !(1 << hart_id());
+    if mask != 0 {
+        let _ = sbi_send_ipi(mask as usize);
+    }
+}
@@ -0,0 +1,680 @@
+use common::SockAddr;
+use core::mem;
+
+use crate::{
+    net::{
+        checksum, ip_send, pseudo_sum, read_header, write_header, Ipv4Addr, NetError, IP_PROTO_TCP,
+        NET_IP,
+    },
+    random::random_u32,
+    timer::{ms_to_ticks, ticks},
+};
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_RST: u8 = 0x04;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+const TCP_CONN_MAX: usize = 8;
+const TCP_BUF_SIZE: usize = 4096;
+const TCP_MSS: usize = 1460;
+const TCP_RTO_MS: u64 = 1000;
+const TCP_RTO_MAX_MS: u64 = 16000;
+const TCP_RETRIES: u32 = 6;
+// 本来は 2MSL だが、ポートをすぐに再利用できるよう短くしている
+const TCP_TIME_WAIT_MS: u64 = 1000;
+
+#[repr(C, packed)]
+struct TcpHeader {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    data_offset: u8,
+    flags: u8,
+    window: u16,
+    checksum: u16,
+    urgent: u16,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+#[derive(Copy, Clone)]
+struct Tcb {
+    state: TcpState,
+    // ソケットから参照されている間は Closed になっても解放しない
+    socket_open: bool,
+    local_port: u16,
+    remote: SockAddr,
+    // 接続を受け付けたリスニングソケットの TCB と、accept 済みかどうか
+    parent: Option<usize>,
+    accepted: bool,
+    backlog: usize,
+
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: usize,
+    snd_mss: usize,
+    syn_unacked: bool,
+    fin_pending: bool,
+    fin_sent: bool,
+    rcv_nxt: u32,
+
+    // rx は受信してまだ読まれていないデータ、tx は snd_una から始まる未確認のデータ
+    rx: [u8; TCP_BUF_SIZE],
+    rx_len: usize,
+    tx: [u8; TCP_BUF_SIZE],
+    tx_len: usize,
+    tx_sent: usize,
+
+    rto: u64,
+    deadline: Option<u64>,
+    retries: u32,
+}
+
+impl Tcb {
+    const fn new() -> Self {
+        Self {
+            state: TcpState::Closed,
+            socket_open: false,
+            local_port: 0,
+            remote: SockAddr {
+                addr: [0; 4],
+                port: 0,
+            },
+            parent: None,
+            accepted: false,
+            backlog: 0,
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_wnd: 0,
+            snd_mss: TCP_MSS,
+            syn_unacked: false,
+            fin_pending: false,
+            fin_sent: false,
+            rcv_nxt: 0,
+            rx: [0; TCP_BUF_SIZE],
+            rx_len: 0,
+            tx: [0; TCP_BUF_SIZE],
+            tx_len: 0,
+            tx_sent: 0,
+            rto: 0,
+            deadline: None,
+            retries: 0,
+        }
+    }
+
+    fn is_free(&self) -> bool {
+        self.state == TcpState::Closed && !self.socket_open
+    }
+
+    fn rx_window(&self) -> usize {
+        TCP_BUF_SIZE - self.rx_len
+    }
+}
+
+static mut TCBS: [Tcb; TCP_CONN_MAX] = [Tcb::new(); TCP_CONN_MAX];
+
+fn tcb(i: usize) -> &'static mut Tcb {
+    unsafe { &mut TCBS[i] }
+}
+
+fn tcb_alloc(local_port: u16, remote: SockAddr) -> Result<usize, NetError> {
+    let i = unsafe { TCBS.iter().position(|t| t.is_free()) }.ok_or(NetError::NoBuffers)?;
+    let t = tcb(i);
+    *t = Tcb::new();
+    t.local_port = local_port;
+    t.remote = remote;
+    t.rto = ms_to_ticks(TCP_RTO_MS);
+    // 初期シーケンス番号は推測されないように乱数で決める
+    let iss = random_u32();
+    t.snd_una = iss;
+    t.snd_nxt = iss;
+    Ok(i)
+}
+
+fn tcb_lookup(local_port: u16, remote: &SockAddr) -> Option<usize> {
+    let tcbs = unsafe { &TCBS };
+    tcbs.iter()
+        .position(|t| {
+            !matches!(t.state, TcpState::Closed | TcpState::Listen)
+                && t.local_port == local_port
+                && t.remote == *remote
+        })
+        .or_else(|| {
+            tcbs.iter()
+                .position(|t| t.state == TcpState::Listen && t.local_port == local_port)
+        })
+}
+
+pub fn tcp_port_in_use(port: u16) -> bool {
+    unsafe { TCBS.iter().any(|t| !t.is_free() && t.local_port == port) }
+}
+
+fn send_segment(t: &Tcb, seq: u32, flags: u8, data: &[u8]) -> Result<(), NetError> {
+    // SYN には MSS オプションを付ける
+    let mut opts = [0; 4];
+    let opts_len = if flags & TCP_FLAG_SYN != 0 {
+        opts.copy_from_slice(&[TCP_OPT_MSS, 4, (TCP_MSS >> 8) as u8, TCP_MSS as u8]);
+        opts.len()
+    } else {
+        0
+    };
+
+    let header_len = mem::size_of::<TcpHeader>() + opts_len;
+    let mut header = [0; mem::size_of::<TcpHeader>()];
+    write_header(
+        &mut header,
+        TcpHeader {
+            src_port: t.local_port.to_be(),
+            dst_port: t.remote.port.to_be(),
+            seq: seq.to_be(),
+            ack: (if flags & TCP_FLAG_ACK != 0 {
+                t.rcv_nxt
+            } else {
+                0
+            })
+            .to_be(),
+            data_offset: ((header_len / 4) << 4) as u8,
+            flags,
+            window: (t.rx_window() as u16).to_be(),
+            checksum: 0,
+            urgent: 0,
+        },
+    );
+    let len = header_len + data.len();
+    let segs: [&[u8]; 3] = [&header, &opts[0..opts_len], data];
+    let sum = checksum(&segs, pseudo_sum(NET_IP, t.remote.addr, IP_PROTO_TCP, len));
+    header[mem::offset_of!(TcpHeader, checksum)..][0..2].copy_from_slice(&sum.to_be_bytes());
+    ip_send(
+        t.remote.addr,
+        IP_PROTO_TCP,
+        &[&header, &opts[0..opts_len], data],
+    )
+}
+
+// 対応する接続がないセグメントには RST を返す
+fn send_reset(src: Ipv4Addr, tcp: &TcpHeader, seg_len: u32) {
+    let mut t = Tcb::new();
+    t.local_port = u16::from_be(tcp.dst_port);
+    t.remote = SockAddr {
+        addr: src,
+        port: u16::from_be(tcp.src_port),
+    };
+    let _ = if tcp.flags & TCP_FLAG_ACK != 0 {
+        send_segment(&t, u32::from_be(tcp.ack), TCP_FLAG_RST, &[])
+    } else {
+        t.rcv_nxt = u32::from_be(tcp.seq).wrapping_add(seg_len);
+        send_segment(&t, 0, TCP_FLAG_RST | TCP_FLAG_ACK, &[])
+    };
+}
+
+fn arm_timer(t: &mut Tcb) {
+    if t.deadline.is_none() {
+        t.deadline = Some(ticks() + t.rto);
+    }
+}
+
+fn send_ack(t: &Tcb) {
+    let _ = send_segment(t, t.snd_nxt, TCP_FLAG_ACK, &[]);
+}
+
+// 送信ウィンドウが許す限りデータを送り、すべて送り終わっていれば FIN も送る
+fn tcp_output(i: usize) {
+    let t = tcb(i);
+    if t.syn_unacked
+        || matches!(
+            t.state,
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent | TcpState::TimeWait
+        )
+    {
+        return;
+    }
+
+    loop {
+        let unsent = t.tx_len - t.tx_sent;
+        let window = t.snd_wnd.saturating_sub(t.tx_sent);
+        let len = unsent.min(window).min(t.snd_mss);
+        if len == 0 {
+            break;
+        }
+        let data = &t.tx[t.tx_sent..(t.tx_sent + len)];
+        if send_segment(t, t.snd_nxt, TCP_FLAG_ACK | TCP_FLAG_PSH, data).is_err() {
+            break;
+        }
+        t.snd_nxt = t.snd_nxt.wrapping_add(len as u32);
+        t.tx_sent += len;
+        arm_timer(t);
+    }
+
+    if t.fin_pending && !t.fin_sent && t.tx_sent == t.tx_len {
+        let _ = send_segment(t, t.snd_nxt, TCP_FLAG_FIN | TCP_FLAG_ACK, &[]);
+        t.snd_nxt = t.snd_nxt.wrapping_add(1);
+        t.fin_sent = true;
+        t.state = match t.state {
+            TcpState::Established | TcpState::SynReceived => TcpState::FinWait1,
+            TcpState::CloseWait => TcpState::LastAck,
+            state => state,
+        };
+        arm_timer(t);
+    } else if t.tx_len > t.tx_sent && t.snd_wnd == 0 {
+        // 相手のウィンドウが開くのを待つ間もタイマーを動かしてプローブを送る
+        arm_timer(t);
+    }
+}
+
+// 確認応答されたところまで送信バッファを進める。FIN が確認されたら true を返す
+fn tcp_acked(t: &mut Tcb, ack: u32) -> bool {
+    let mut n = ack.wrapping_sub(t.snd_una) as usize;
+    t.snd_una = ack;
+    if n > 0 && t.syn_unacked {
+        t.syn_unacked = false;
+        n -= 1;
+    }
+    let data = n.min(t.tx_sent);
+    t.tx.copy_within(data..t.tx_len, 0);
+    t.tx_len -= data;
+    t.tx_sent -= data;
+    n -= data;
+
+    t.retries = 0;
+    t.rto = ms_to_ticks(TCP_RTO_MS);
+    t.deadline = None;
+    if t.snd_una != t.snd_nxt {
+        arm_timer(t);
+    }
+    n > 0 && t.fin_sent
+}
+
+fn parse_mss(opts: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i < opts.len() {
+        match opts[i] {
+            TCP_OPT_END => break,
+            TCP_OPT_NOP => i += 1,
+            kind => {
+                let len = *opts.get(i + 1)? as usize;
+                if kind == TCP_OPT_MSS && len == 4 && i + 4 <= opts.len() {
+                    return Some(((opts[i + 2] as usize) << 8) | opts[i + 3] as usize);
+                }
+                if len < 2 {
+                    break;
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
+pub fn tcp_input(src: Ipv4Addr, payload: &[u8]) {
+    let Some(tcp) = read_header::<TcpHeader>(payload) else {
+        return;
+    };
+    let header_len = ((tcp.data_offset >> 4) as usize) * 4;
+    if header_len < mem::size_of::<TcpHeader>() || header_len > payload.len() {
+        return;
+    }
+    if checksum(
+        &[payload],
+        pseudo_sum(src, NET_IP, IP_PROTO_TCP, payload.len()),
+    ) != 0
+    {
+        return;
+    }
+
+    let flags = tcp.flags;
+    let seq = u32::from_be(tcp.seq);
+    let ack = u32::from_be(tcp.ack);
+    let window = u16::from_be(tcp.window) as usize;
+    let opts = &payload[mem::size_of::<TcpHeader>()..header_len];
+    let data = &payload[header_len..];
+    let seg_len =
+        data.len() as u32 + (flags & TCP_FLAG_SYN != 0) as u32 + (flags & TCP_FLAG_FIN != 0) as u32;
+    let remote = SockAddr {
+        addr: src,
+        port: u16::from_be(tcp.src_port),
+    };
+
+    let Some(i) = tcb_lookup(u16::from_be(tcp.dst_port), &remote) else {
+        if flags & TCP_FLAG_RST == 0 {
+            send_reset(src, &tcp, seg_len);
+        }
+        return;
+    };
+    let t = tcb(i);
+
+    match t.state {
+        TcpState::Listen => {
+            if flags & TCP_FLAG_RST != 0 {
+                return;
+            }
+            if flags & TCP_FLAG_ACK != 0 || flags & TCP_FLAG_SYN == 0 {
+                send_reset(src, &tcp, seg_len);
+                return;
+            }
+            let pending = unsafe {
+                TCBS.iter()
+                    .filter(|c| c.parent == Some(i) && !c.accepted && !c.is_free())
+                    .count()
+            };
+            if pending >= t.backlog {
+                return;
+            }
+
+            let Ok(c) = tcb_alloc(t.local_port, remote) else {
+                return;
+            };
+            let child = tcb(c);
+            child.state = TcpState::SynReceived;
+            child.parent = Some(i);
+            child.rcv_nxt = seq.wrapping_add(1);
+            child.snd_wnd = window;
+            child.snd_mss = parse_mss(opts).unwrap_or(536).min(TCP_MSS);
+            child.syn_unacked = true;
+            let _ = send_segment(child, child.snd_nxt, TCP_FLAG_SYN | TCP_FLAG_ACK, &[]);
+            child.snd_nxt = child.snd_nxt.wrapping_add(1);
+            arm_timer(child);
+        }
+        TcpState::SynSent => {
+            if flags & TCP_FLAG_ACK != 0 && ack != t.snd_nxt {
+                if flags & TCP_FLAG_RST == 0 {
+                    send_reset(src, &tcp, seg_len);
+                }
+                return;
+            }
+            if flags & TCP_FLAG_RST != 0 {
+                if flags & TCP_FLAG_ACK != 0 {
+                    t.state = TcpState::Closed;
+                }
+                return;
+            }
+            if flags & TCP_FLAG_SYN == 0 || flags & TCP_FLAG_ACK == 0 {
+                return;
+            }
+
+            t.rcv_nxt = seq.wrapping_add(1);
+            t.snd_wnd = window;
+            t.snd_mss = parse_mss(opts).unwrap_or(536).min(TCP_MSS);
+            tcp_acked(t, ack);
+            t.state = TcpState::Established;
+            send_ack(t);
+        }
+        TcpState::Closed => {}
+        _ => tcp_input_synchronized(i, flags, seq, ack, window, data),
+    }
+}
+
+fn tcp_input_synchronized(i: usize, flags: u8, seq: u32, ack: u32, window: usize, data: &[u8]) {
+    let t = tcb(i);
+
+    // 順番どおりのセグメントだけを受け付ける。それ以外には ACK を返して再送を促す
+    if seq != t.rcv_nxt {
+        if flags & TCP_FLAG_RST == 0 {
+            send_ack(t);
+        }
+        return;
+    }
+    if flags & TCP_FLAG_RST != 0 {
+        t.state = TcpState::Closed;
+        t.deadline = None;
+        return;
+    }
+    if flags & TCP_FLAG_SYN != 0 {
+        // 接続中の SYN は想定外なのでリセットする
+        let _ = send_segment(t, t.snd_nxt, TCP_FLAG_RST, &[]);
+        t.state = TcpState::Closed;
+        return;
+    }
+    if flags & TCP_FLAG_ACK == 0 {
+        return;
+    }
+
+    let acked = ack.wrapping_sub(t.snd_una);
+    let in_flight = t.snd_nxt.wrapping_sub(t.snd_una);
+    if acked > in_flight {
+        send_ack(t);
+        return;
+    }
+    let fin_acked = acked > 0 && tcp_acked(t, ack);
+    t.snd_wnd = window;
+
+    match t.state {
+        TcpState::SynReceived if !t.syn_unacked => t.state = TcpState::Established,
+        TcpState::FinWait1 if fin_acked => t.state = TcpState::FinWait2,
+        TcpState::Closing if fin_acked => enter_time_wait(t),
+        TcpState::LastAck if fin_acked => {
+            t.state = TcpState::Closed;
+            t.deadline = None;
+            return;
+        }
+        _ => {}
+    }
+
+    let mut need_ack = false;
+    if !data.is_empty()
+        && matches!(
+            t.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        )
+    {
+        // 入りきらない分は捨て、相手の再送に任せる
+        let len = data.len().min(t.rx_window());
+        t.rx[t.rx_len..(t.rx_len + len)].copy_from_slice(&data[0..len]);
+        t.rx_len += len;
+        t.rcv_nxt = t.rcv_nxt.wrapping_add(len as u32);
+        need_ack = true;
+    }
+
+    if flags & TCP_FLAG_FIN != 0 && seq.wrapping_add(data.len() as u32) == t.rcv_nxt {
+        t.rcv_nxt = t.rcv_nxt.wrapping_add(1);
+        need_ack = true;
+        match t.state {
+            TcpState::SynReceived | TcpState::Established => t.state = TcpState::CloseWait,
+            TcpState::FinWait1 => t.state = TcpState::Closing,
+            TcpState::FinWait2 => enter_time_wait(t),
+            _ => {}
+        }
+    }
+
+    if need_ack {
+        send_ack(t);
+    }
+    tcp_output(i);
+}
+
+fn enter_time_wait(t: &mut Tcb) {
+    t.state = TcpState::TimeWait;
+    t.deadline = Some(ticks() + ms_to_ticks(TCP_TIME_WAIT_MS));
+}
+
+// 再送タイマーを処理する。net_poll のたびに呼ばれる
+pub fn tcp_timer() {
+    let now = ticks();
+    for i in 0..TCP_CONN_MAX {
+        let t = tcb(i);
+        match t.deadline {
+            Some(deadline) if now >= deadline => {}
+            _ => continue,
+        }
+        t.deadline = None;
+
+        if t.state == TcpState::TimeWait {
+            t.state = TcpState::Closed;
+            continue;
+        }
+
+        t.retries += 1;
+        if t.retries > TCP_RETRIES {
+            let _ = send_segment(t, t.snd_nxt, TCP_FLAG_RST, &[]);
+            t.state = TcpState::Closed;
+            continue;
+        }
+        t.rto = (t.rto * 2).min(ms_to_ticks(TCP_RTO_MAX_MS));
+
+        // go-back-N: 確認されていないところから送り直す
+        t.snd_nxt = t.snd_una;
+        t.tx_sent = 0;
+        t.fin_sent = false;
+        if t.syn_unacked {
+            let flags = match t.state {
+                TcpState::SynSent => TCP_FLAG_SYN,
+                _ => TCP_FLAG_SYN | TCP_FLAG_ACK,
+            };
+            let _ = send_segment(t, t.snd_nxt, flags, &[]);
+            t.snd_nxt = t.snd_nxt.wrapping_add(1);
+            arm_timer(t);
+            continue;
+        }
+        // ウィンドウが 0 のままなら 1 バイトだけ送ってプローブする
+        if t.snd_wnd == 0 && t.tx_len > 0 {
+            t.snd_wnd = 1;
+        }
+        tcp_output(i);
+    }
+}
+
+// いちばん早い再送タイマーの時刻
+pub fn tcp_next_deadline() -> Option<u64> {
+    unsafe { TCBS.iter().filter_map(|t| t.deadline).min() }
+}
+
+pub fn tcp_listen(port: u16, backlog: usize) -> Result<usize, NetError> {
+    let i = tcb_alloc(
+        port,
+        SockAddr {
+            addr: [0; 4],
+            port: 0,
+        },
+    )?;
+    let t = tcb(i);
+    t.state = TcpState::Listen;
+    t.socket_open = true;
+    t.backlog = backlog.max(1);
+    Ok(i)
+}
+
+// 確立済みでまだ accept されていない接続を返す
+pub fn tcp_accept(listener: usize) -> Option<(usize, SockAddr)> {
+    let i = unsafe {
+        TCBS.iter().position(|t| {
+            t.parent == Some(listener)
+                && !t.accepted
+                && !matches!(t.state, TcpState::Closed | TcpState::SynReceived)
+        })
+    }?;
+    let t = tcb(i);
+    t.accepted = true;
+    t.socket_open = true;
+    Some((i, t.remote))
+}
+
+pub fn tcp_connect(local_port: u16, remote: SockAddr) -> Result<usize, NetError> {
+    let i = tcb_alloc(local_port, remote)?;
+    let t = tcb(i);
+    t.state = TcpState::SynSent;
+    t.socket_open = true;
+    t.syn_unacked = true;
+    send_segment(t, t.snd_nxt, TCP_FLAG_SYN, &[])?;
+    t.snd_nxt = t.snd_nxt.wrapping_add(1);
+    arm_timer(t);
+    Ok(i)
+}
+
+pub fn tcp_state(i: usize) -> TcpState {
+    tcb(i).state
+}
+
+// 送信バッファに入った分だけ送り、そのバイト数を返す
+pub fn tcp_send(i: usize, data: &[u8]) -> Result<usize, NetError> {
+    let t = tcb(i);
+    if !matches!(t.state, TcpState::Established | TcpState::CloseWait) || t.fin_pending {
+        return Err(NetError::NotConnected);
+    }
+    let len = data.len().min(TCP_BUF_SIZE - t.tx_len);
+    t.tx[t.tx_len..(t.tx_len + len)].copy_from_slice(&data[0..len]);
+    t.tx_len += len;
+    tcp_output(i);
+    Ok(len)
+}
+
+pub fn tcp_send_space(i: usize) -> usize {
+    TCP_BUF_SIZE - tcb(i).tx_len
+}
+
+// 受信済みのデータを取り出す。相手が FIN を送ってきていてデータがなければ 0 を返す
+pub fn tcp_recv(i: usize, buf: &mut [u8]) -> Result<usize, NetError> {
+    let t = tcb(i);
+    if t.rx_len == 0 && t.state == TcpState::Closed {
+        return Err(NetError::Reset);
+    }
+    let window_was_closed = t.rx_window() < t.snd_mss;
+    let len = buf.len().min(t.rx_len);
+    buf[0..len].copy_from_slice(&t.rx[0..len]);
+    t.rx.copy_within(len..t.rx_len, 0);
+    t.rx_len -= len;
+    // ウィンドウが開いたことを相手に知らせる
+    if window_was_closed && len > 0 && t.state != TcpState::Closed {
+        send_ack(t);
+    }
+    Ok(len)
+}
+
+// recv がブロックせずに戻れる状態なら true
+pub fn tcp_readable(i: usize) -> bool {
+    let t = tcb(i);
+    t.rx_len > 0
+        || !matches!(
+            t.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 | TcpState::SynReceived
+        )
+}
+
+pub fn tcp_close(i: usize) {
+    let t = tcb(i);
+    t.socket_open = false;
+    match t.state {
+        TcpState::Listen => {
+            t.state = TcpState::Closed;
+            // まだ accept されていない接続はリセットする
+            for c in 0..TCP_CONN_MAX {
+                let child = tcb(c);
+                if child.parent == Some(i) && !child.accepted && !child.is_free() {
+                    let _ = send_segment(child, child.snd_nxt, TCP_FLAG_RST, &[]);
+                    child.state = TcpState::Closed;
+                    child.deadline = None;
+                }
+                if child.parent == Some(i) {
+                    child.parent = None;
+                }
+            }
+        }
+        TcpState::SynSent => {
+            t.state = TcpState::Closed;
+            t.deadline = None;
+        }
+        TcpState::SynReceived | TcpState::Established | TcpState::CloseWait => {
+            t.fin_pending = true;
+            tcp_output(i);
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,87 @@
+use common::{read_csr, Timespec, CLOCK_MONOTONIC, CLOCK_REALTIME};
+
+use crate::{
+    rtc::rtc,
+    sbi::sbi_set_timer,
+    smp::{hart_id, HARTS_MAX},
+    PLATFORM, PM,
+};
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+// hart ごとに今セットしているタイマーの時刻。何も待っていなければ u64::MAX
+static mut TIMER_NEXT: [u64; HARTS_MAX] = [u64::MAX; HARTS_MAX];
+
+pub fn ticks() -> u64 {
+    loop {
+        let hi = read_csr!("timeh");
+        let lo = read_csr!("time");
+        if hi == read_csr!("timeh") {
+            return ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
+
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    unsafe { PLATFORM.timebase_freq as u64 * ms / 1000 }
+}
+
+// 起動してからの時間。ticks に 10^9 を掛けるとあふれるので、秒とその余りに分けて変換する
+pub fn clock_monotonic() -> Timespec {
+    let freq = unsafe { PLATFORM.timebase_freq as u64 };
+    let ticks = ticks();
+    Timespec {
+        sec: ticks / freq,
+        nsec: ((ticks % freq) * NSEC_PER_SEC / freq) as u32,
+    }
+}
+
+// RTC がなければ起動した時刻を 1970-01-01 とみなす
+pub fn clock_realtime() -> Timespec {
+    match rtc() {
+        Some(rtc) => {
+            let ns = rtc.now_ns();
+            Timespec {
+                sec: ns / NSEC_PER_SEC,
+                nsec: (ns % NSEC_PER_SEC) as u32,
+            }
+        }
+        None => clock_monotonic(),
+    }
+}
+
+pub fn clock_gettime(clock: u32) -> Option<Timespec> {
+    match clock {
+        CLOCK_REALTIME => Some(clock_realtime()),
+        CLOCK_MONOTONIC => Some(clock_monotonic()),
+        _ => None,
+    }
+}
+
+// ファームウェアがタイマーを残していても割り込みが来ないようにしておく
+pub fn timer_init() {
+    unsafe { TIMER_NEXT[hart_id()] = u64::MAX };
+    sbi_set_timer(u64::MAX);
+}
+
+// この hart に `deadline` でタイマー割り込みが来るようにする。もっと早い時刻をセット済みならそのまま
+pub fn timer_arm(deadline: u64) {
+    unsafe {
+        let next = &mut TIMER_NEXT[hart_id()];
+        if deadline < *next {
+            *next = deadline;
+            sbi_set_timer(deadline);
+        }
+    }
+}
+
+// 時刻になったプロセスを起こし、次に起こす時刻でタイマーをセットし直す
+pub fn timer_handle_interrupt() {
+    unsafe {
+        TIMER_NEXT[hart_id()] = u64::MAX;
+        sbi_set_timer(u64::MAX);
+        if let Some(next) = PM.wakeup_expired(ticks()) {
+            timer_arm(next);
+        }
+    }
+}
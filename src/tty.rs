@@ -0,0 +1,262 @@
+use common::{
+    Termios, ECHO, EINTR, EOF, ICANON, ICRNL, ISIG, NCCS, ONLCR, SIGINT, VEOF, VERASE, VINTR, VKILL,
+};
+
+use core::ptr;
+
+use crate::{
+    console::{console_can_wait, console_flush, getchar, putchar},
+    timer::{ms_to_ticks, ticks},
+    PM,
+};
+
+const TTY_LINE_MAX: usize = 256;
+const TTY_READY_SIZE: usize = 512;
+// SBI のコンソールは割り込みがないので、この間隔で入力を見に行く
+const TTY_POLL_MS: u64 = 10;
+// 端末によっては Backspace で DEL ではなく BS が届くので、どちらも消去として扱う
+const ASCII_BS: u8 = 0x08;
+const ASCII_DEL: u8 = 0x7f;
+
+const TTY_DEFAULT_CC: [u8; NCCS] = {
+    let mut cc = [0; NCCS];
+    cc[VINTR] = 0x03;
+    cc[VERASE] = ASCII_DEL;
+    cc[VKILL] = 0x15;
+    cc[VEOF] = 0x04;
+    cc
+};
+
+// コンソールとユーザープロセスの間に入る行規則 (line discipline)
+struct Tty {
+    termios: Termios,
+    // 編集中の行
+    line: [u8; TTY_LINE_MAX],
+    line_len: usize,
+    // 読み出せる状態になった入力
+    ready: [u8; TTY_READY_SIZE],
+    ready_read: usize,
+    ready_write: usize,
+    // Ctrl-D で入力の終わりが届いた
+    eof: bool,
+    // Ctrl-C で SIGINT を送るプロセス
+    foreground: Option<u32>,
+}
+
+static mut TTY: Tty = Tty {
+    termios: Termios {
+        iflag: ICRNL,
+        oflag: ONLCR,
+        lflag: ICANON | ECHO | ISIG,
+        cc: TTY_DEFAULT_CC,
+    },
+    line: [0; TTY_LINE_MAX],
+    line_len: 0,
+    ready: [0; TTY_READY_SIZE],
+    ready_read: 0,
+    ready_write: 0,
+    eof: false,
+    foreground: None,
+};
+
+impl Tty {
+    fn lflag(&self, flag: u32) -> bool {
+        self.termios.lflag & flag != 0
+    }
+
+    fn output(&self, ch: u8) {
+        if ch == b'\n' && self.termios.oflag & ONLCR != 0 {
+            putchar(b'\r');
+        }
+        putchar(ch);
+    }
+
+    // 制御文字は ^C のように表示する
+    fn echo(&self, ch: u8) {
+        if !self.lflag(ECHO) {
+            return;
+        }
+        match ch {
+            b'\n' | b'\t' => self.output(ch),
+            0..=0x1f => {
+                self.output(b'^');
+                self.output(ch + b'@');
+            }
+            _ => self.output(ch),
+        }
+    }
+
+    fn erase(&mut self) -> bool {
+        if self.line_len == 0 {
+            return false;
+        }
+        self.line_len -= 1;
+        if self.lflag(ECHO) {
+            // 制御文字は 2 文字で表示しているので 2 文字消す
+            let width = if self.line[self.line_len] < 0x20 {
+                2
+            } else {
+                1
+            };
+            for _ in 0..width {
+                self.output(ASCII_BS);
+                self.output(b' ');
+                self.output(ASCII_BS);
+            }
+        }
+        true
+    }
+
+    fn push_ready(&mut self, ch: u8) {
+        if self.ready_write - self.ready_read < TTY_READY_SIZE {
+            self.ready[self.ready_write % TTY_READY_SIZE] = ch;
+            self.ready_write += 1;
+        }
+    }
+
+    // 編集中の行を読み出せるようにする
+    fn commit_line(&mut self) {
+        for i in 0..self.line_len {
+            self.push_ready(self.line[i]);
+        }
+        self.line_len = 0;
+    }
+
+    fn input(&mut self, mut ch: u8) {
+        if ch == b'\r' && self.termios.iflag & ICRNL != 0 {
+            ch = b'\n';
+        }
+
+        let cc = self.termios.cc;
+        if self.lflag(ISIG) && ch == cc[VINTR] {
+            // 編集中の行を捨て、読み手には空行を渡してプロンプトを出し直させる
+            self.echo(ch);
+            self.output(b'\n');
+            self.line_len = 0;
+            if self.lflag(ICANON) {
+                self.push_ready(b'\n');
+            }
+            if let Some(pid) = self.foreground {
+                let _ = unsafe { PM.kill(pid, SIGINT) };
+            }
+            return;
+        }
+
+        if !self.lflag(ICANON) {
+            self.echo(ch);
+            self.push_ready(ch);
+            return;
+        }
+
+        match ch {
+            _ if ch == cc[VERASE] || ch == ASCII_BS || ch == ASCII_DEL => {
+                self.erase();
+            }
+            _ if ch == cc[VKILL] => while self.erase() {},
+            _ if ch == cc[VEOF] => {
+                // 行の途中なら改行なしでその行を渡し、空なら入力の終わりを知らせる
+                if self.line_len == 0 {
+                    self.eof = true;
+                }
+                self.commit_line();
+            }
+            b'\n' => {
+                self.echo(ch);
+                self.line[self.line_len] = ch;
+                self.line_len += 1;
+                self.commit_line();
+            }
+            // 改行のための 1 バイトは残しておく
+            _ if self.line_len < TTY_LINE_MAX - 1 => {
+                self.echo(ch);
+                self.line[self.line_len] = ch;
+                self.line_len += 1;
+            }
+            _ => {}
+        }
+    }
+
+    fn pop_ready(&mut self) -> Option<u8> {
+        if self.ready_read == self.ready_write {
+            return None;
+        }
+        let ch = self.ready[self.ready_read % TTY_READY_SIZE];
+        self.ready_read += 1;
+        Some(ch)
+    }
+}
+
+fn tty() -> &'static mut Tty {
+    unsafe { &mut TTY }
+}
+
+// 入力を待つプロセスが眠るチャネル
+fn tty_chan() -> usize {
+    ptr::addr_of!(TTY) as usize
+}
+
+// コンソールに届いている入力をすべて行規則に通す。
+// 実行中のプロセスを Ctrl-C で止められるよう、割り込みのたびにも呼ばれる
+pub fn tty_poll() {
+    let tty = tty();
+    let mut received = false;
+    loop {
+        let ch = getchar();
+        if ch < 0 {
+            break;
+        }
+        tty.input(ch as u8);
+        received = true;
+    }
+    console_flush();
+    if received {
+        unsafe { PM.wakeup(tty_chan()) };
+    }
+}
+
+// 1 文字読み出す。入力の終わりなら EOF を、待っている間にシグナルが届いたら EINTR を返す
+pub fn tty_getchar() -> u32 {
+    loop {
+        tty_poll();
+        let tty = tty();
+        if let Some(ch) = tty.pop_ready() {
+            return ch as u32;
+        }
+        if tty.eof {
+            tty.eof = false;
+            return EOF;
+        }
+        if unsafe { PM.signal_pending() } {
+            return EINTR;
+        }
+
+        // 割り込みで入力が届くなら tty_poll が起こしてくれる
+        let deadline = if console_can_wait() {
+            None
+        } else {
+            Some(ticks() + ms_to_ticks(TTY_POLL_MS))
+        };
+        unsafe { PM.sleep(tty_chan(), deadline) };
+    }
+}
+
+pub fn tty_set_foreground(pid: u32) {
+    tty().foreground = Some(pid);
+}
+
+pub fn tty_putchar(ch: u8) {
+    tty().output(ch);
+}
+
+pub fn tty_getattr() -> Termios {
+    tty().termios
+}
+
+pub fn tty_setattr(termios: &Termios) {
+    let tty = tty();
+    // カノニカルモードをやめるときは、編集中の行をそのまま読めるようにする
+    if tty.lflag(ICANON) && termios.lflag & ICANON == 0 {
+        tty.commit_line();
+    }
+    tty.termios = *termios;
+}
@@ -0,0 +1,113 @@
+use crate::{plic::plic_enable, PLATFORM};
+use core::ptr::{read_volatile, write_volatile};
+
+// QEMU virt の NS16550A (レジスタは 1 バイトおき)
+const UART_RBR: usize = 0;
+const UART_THR: usize = 0;
+const UART_IER: usize = 1;
+const UART_FCR: usize = 2;
+const UART_LCR: usize = 3;
+const UART_MCR: usize = 4;
+const UART_LSR: usize = 5;
+
+const UART_IER_RDI: u8 = 1 << 0;
+const UART_FCR_ENABLE: u8 = 1 << 0;
+const UART_FCR_CLEAR: u8 = (1 << 1) | (1 << 2);
+const UART_LCR_8N1: u8 = 0x03;
+// DTR, RTS と、割り込みを有効にする OUT2
+const UART_MCR_DTR_RTS_OUT2: u8 = 0x0b;
+const UART_LSR_DR: u8 = 1 << 0;
+const UART_LSR_THRE: u8 = 1 << 5;
+
+const UART_INPUT_SIZE: usize = 256;
+
+pub struct Uart {
+    base: usize,
+    irq: u32,
+    input: [u8; UART_INPUT_SIZE],
+    input_read: usize,
+    input_write: usize,
+}
+
+impl Uart {
+    fn read(&self, reg: usize) -> u8 {
+        unsafe { read_volatile((self.base + reg) as *const u8) }
+    }
+
+    fn write(&self, reg: usize, value: u8) {
+        unsafe { write_volatile((self.base + reg) as *mut u8, value) }
+    }
+
+    // ボーレートはファームウェアが設定したものをそのまま使う
+    fn init(&self) {
+        self.write(UART_IER, 0);
+        self.write(UART_LCR, UART_LCR_8N1);
+        self.write(UART_FCR, UART_FCR_ENABLE | UART_FCR_CLEAR);
+        self.write(UART_MCR, UART_MCR_DTR_RTS_OUT2);
+        self.write(UART_IER, UART_IER_RDI);
+    }
+
+    pub fn putchar(&self, ch: u8) {
+        while self.read(UART_LSR) & UART_LSR_THRE == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(UART_THR, ch);
+    }
+
+    // 受信 FIFO を空にして入力用のリングバッファに移す。あふれた分は捨てる
+    pub fn handle_interrupt(&mut self) {
+        while self.read(UART_LSR) & UART_LSR_DR != 0 {
+            let ch = self.read(UART_RBR);
+            if self.input_write - self.input_read < UART_INPUT_SIZE {
+                self.input[self.input_write % UART_INPUT_SIZE] = ch;
+                self.input_write += 1;
+            }
+        }
+    }
+
+    pub fn has_input(&self) -> bool {
+        self.input_read != self.input_write
+    }
+
+    pub fn getchar(&mut self) -> Option<u8> {
+        if !self.has_input() {
+            return None;
+        }
+        let ch = self.input[self.input_read % UART_INPUT_SIZE];
+        self.input_read += 1;
+        Some(ch)
+    }
+}
+
+static mut UART: Option<Uart> = None;
+
+// デバイスツリーに UART があれば、SBI の代わりに直接使う
+pub fn uart_init() {
+    let Some(region) = (unsafe { PLATFORM.uart }) else {
+        return;
+    };
+    let uart = Uart {
+        base: region.base,
+        irq: region.irq,
+        input: [0; UART_INPUT_SIZE],
+        input_read: 0,
+        input_write: 0,
+    };
+    uart.init();
+    plic_enable(uart.irq);
+    unsafe { UART = Some(uart) };
+}
+
+pub fn uart() -> Option<&'static mut Uart> {
+    unsafe { UART.as_mut() }
+}
+
+pub fn uart_irq() -> Option<u32> {
+    uart().map(|uart| uart.irq)
+}
+
+pub fn uart_handle_interrupt() {
+    if let Some(uart) = uart() {
+        uart.handle_interrupt();
+    }
+}
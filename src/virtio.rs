@@ -1,49 +1,70 @@
-use common::{align_up, PAGE_SIZE, VIRTIO_BLK_PADDR};
+use common::{align_up, PAGE_SIZE};
 
-use crate::{memory::alloc_pages, println};
+use crate::memory::alloc_pages;
 use core::{
     arch::asm,
     mem,
     ptr::{self, read_volatile, write_volatile},
 };
 
-const VIRTQ_ENTRY_NUM: usize = 16;
-const VIRTIO_DEVICE_BLK: u32 = 2;
+// デバイスツリーから読み取る virtio-mmio スロットの最大数
+pub const VIRTIO_MMIO_COUNT: usize = 8;
+pub const VIRTQ_ENTRY_NUM: usize = 64;
+pub const VIRTIO_DEVICE_NET: u32 = 1;
+pub const VIRTIO_DEVICE_BLK: u32 = 2;
+pub const VIRTIO_DEVICE_CONSOLE: u32 = 3;
+pub const VIRTIO_DEVICE_RNG: u32 = 4;
+pub const VIRTIO_DEVICE_9P: u32 = 9;
+pub const VIRTIO_DEVICE_INPUT: u32 = 18;
+const VIRTIO_MAGIC: u32 = 0x74726976;
 const VIRTIO_REG_MAGIC: usize = 0x00;
 const VIRTIO_REG_VERSION: usize = 0x04;
 const VIRTIO_REG_DEVICE_ID: usize = 0x08;
+const VIRTIO_REG_DEVICE_FEATURES: usize = 0x10;
+const VIRTIO_REG_DEVICE_FEATURES_SEL: usize = 0x14;
+const VIRTIO_REG_DRIVER_FEATURES: usize = 0x20;
+const VIRTIO_REG_DRIVER_FEATURES_SEL: usize = 0x24;
+const VIRTIO_REG_GUEST_PAGE_SIZE: usize = 0x28;
 const VIRTIO_REG_QUEUE_SEL: usize = 0x30;
-// const VIRTIO_REG_QUEUE_NUM_MAX: usize = 0x34;
+const VIRTIO_REG_QUEUE_NUM_MAX: usize = 0x34;
 const VIRTIO_REG_QUEUE_NUM: usize = 0x38;
 const VIRTIO_REG_QUEUE_ALIGN: usize = 0x3c;
 const VIRTIO_REG_QUEUE_PFN: usize = 0x40;
-// const VIRTIO_REG_QUEUE_READY: u32 = 0x44;
-const VIRTIO_REG_QUEUE_NOTIFY: u32 = 0x50;
+const VIRTIO_REG_QUEUE_READY: usize = 0x44;
+const VIRTIO_REG_QUEUE_NOTIFY: usize = 0x50;
+const VIRTIO_REG_INTERRUPT_STATUS: usize = 0x60;
+const VIRTIO_REG_INTERRUPT_ACK: usize = 0x64;
 const VIRTIO_REG_DEVICE_STATUS: usize = 0x70;
+const VIRTIO_REG_QUEUE_DESC_LOW: usize = 0x80;
+const VIRTIO_REG_QUEUE_DESC_HIGH: usize = 0x84;
+const VIRTIO_REG_QUEUE_DRIVER_LOW: usize = 0x90;
+const VIRTIO_REG_QUEUE_DRIVER_HIGH: usize = 0x94;
+const VIRTIO_REG_QUEUE_DEVICE_LOW: usize = 0xa0;
+const VIRTIO_REG_QUEUE_DEVICE_HIGH: usize = 0xa4;
+const VIRTIO_REG_CONFIG_GENERATION: usize = 0xfc;
 const VIRTIO_REG_DEVICE_CONFIG: usize = 0x100;
 const VIRTIO_STATUS_ACK: u32 = 1;
 const VIRTIO_STATUS_DRIVER: u32 = 2;
 const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
 const VIRTIO_STATUS_FEAT_OK: u32 = 8;
-const VIRTQ_DESC_F_NEXT: u32 = 1;
-const VIRTQ_DESC_F_WRITE: u32 = 2;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+pub const VIRTQ_DESC_F_NEXT: u32 = 1;
+pub const VIRTQ_DESC_F_WRITE: u32 = 2;
 // const VIRTQ_AVAIL_F_NO_INTERRUPT: u32 = 1;
-const VIRTIO_BLK_T_IN: u32 = 0;
-const VIRTIO_BLK_T_OUT: u32 = 1;
-
 #[repr(C, packed)]
-struct VirtqDesc {
-    addr: u64,
-    len: u32,
-    flags: u16,
-    next: u16,
+pub struct VirtqDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
 }
 
+// avail と used のリングはこのヘッダのすぐ後ろに続く。長さはネゴシエートした
+// キューのサイズで決まるので、VirtioVirtq を通して読み書きする
 #[repr(C, packed)]
 struct VirtqAvail {
     flags: u16,
     index: u16,
-    ring: [u16; VIRTQ_ENTRY_NUM],
 }
 
 #[repr(C, packed)]
@@ -56,190 +77,338 @@ struct VirtqUsedElem {
 struct VirtqUsed {
     flags: u16,
     index: u16,
-    ring: [VirtqUsedElem; VIRTQ_ENTRY_NUM],
 }
 
-const SIZE_OF_U16: usize = core::mem::size_of::<u16>();
-const SIZE_OF_U32: usize = core::mem::size_of::<u32>();
-const SIZE_OF_U64: usize = core::mem::size_of::<u64>();
-
-const SIZE: usize = (SIZE_OF_U64 + SIZE_OF_U16 + SIZE_OF_U32 + SIZE_OF_U16) * VIRTQ_ENTRY_NUM
-    + (SIZE_OF_U16 + SIZE_OF_U16 + SIZE_OF_U16 * VIRTQ_ENTRY_NUM as usize);
-
-#[repr(C, packed)]
-struct VirtioVirtq {
-    descs: [VirtqDesc; VIRTQ_ENTRY_NUM],
-    avail: VirtqAvail,
-    pad: [u8; (PAGE_SIZE - (SIZE % PAGE_SIZE)) / mem::size_of::<u8>()],
-    used: VirtqUsed,
-
+pub struct VirtioVirtq {
     queue_index: u32,
-    used_index: *mut u16,
+    pub num: usize,
+    descs: *mut VirtqDesc,
+    avail: *mut VirtqAvail,
+    used: *mut VirtqUsed,
+
+    free_head: u16,
+    pub num_free: usize,
     last_used_index: u16,
 }
 
-#[repr(C, packed)]
-struct VirtioBlkReq {
-    type_: u32,
-    reserved: u32,
-    sector: u64,
-    data: [u8; 512],
-    status: u8,
-}
+impl VirtioVirtq {
+    pub fn desc(&mut self, index: u16) -> &mut VirtqDesc {
+        unsafe { self.descs.add(index as usize).as_mut().unwrap() }
+    }
 
-fn virtio_reg_read32(offset: usize) -> u32 {
-    unsafe { read_volatile((VIRTIO_BLK_PADDR + offset) as *const u32) }
-}
+    // 空きリストから `n` 個のディスクリプタを取り出す。リストにつないだ順に
+    // `next` でつながっているので、そのままチェーンとして使える
+    pub fn alloc_descs(&mut self, n: usize) -> Option<u16> {
+        if n == 0 || self.num_free < n {
+            return None;
+        }
 
-fn virtio_reg_read64(offset: usize) -> u64 {
-    unsafe { read_volatile((VIRTIO_BLK_PADDR + offset) as *const u64) }
-}
+        let head = self.free_head;
+        let mut tail = head;
+        for _ in 1..n {
+            tail = self.desc(tail).next;
+        }
+        self.free_head = self.desc(tail).next;
+        self.num_free -= n;
+        Some(head)
+    }
 
-fn virtio_reg_write32(offset: usize, value: u32) {
-    unsafe { write_volatile((VIRTIO_BLK_PADDR + offset) as *mut u32, value) }
-}
+    pub fn free_descs(&mut self, head: u16) {
+        let mut tail = head;
+        let mut n = 1;
+        while self.desc(tail).flags & VIRTQ_DESC_F_NEXT as u16 != 0 {
+            tail = self.desc(tail).next;
+            n += 1;
+        }
+        let free_head = self.free_head;
+        let desc = self.desc(tail);
+        desc.flags = 0;
+        desc.next = free_head;
+        self.free_head = head;
+        self.num_free += n;
+    }
+
+    fn push_avail(&mut self, head: u16) {
+        unsafe {
+            let avail = self.avail.as_mut().unwrap();
+            let ring = (self.avail as *mut u8).add(mem::size_of::<VirtqAvail>()) as *mut u16;
+            write_volatile(ring.add(avail.index as usize % self.num), head);
+            asm!("fence");
+            avail.index = avail.index.wrapping_add(1);
+            asm!("fence");
+        }
+    }
+
+    pub fn pop_used(&mut self) -> Option<u16> {
+        self.pop_used_len().map(|(id, _)| id)
+    }
+
+    // デバイスがチェーンに書き込んだバイト数もあわせて返す
+    pub fn pop_used_len(&mut self) -> Option<(u16, u32)> {
+        unsafe {
+            let used_index = read_volatile(ptr::addr_of!((*self.used).index));
+            if self.last_used_index == used_index {
+                return None;
+            }
 
-fn virtio_reg_fetch_and_or32(offset: usize, value: u32) {
-    let current_value = virtio_reg_read32(offset);
-    virtio_reg_write32(offset, current_value | value);
+            asm!("fence");
+            let ring =
+                (self.used as *mut u8).add(mem::size_of::<VirtqUsed>()) as *mut VirtqUsedElem;
+            let elem = ring.add(self.last_used_index as usize % self.num);
+            let id = read_volatile(ptr::addr_of!((*elem).id));
+            let len = read_volatile(ptr::addr_of!((*elem).len));
+            self.last_used_index = self.last_used_index.wrapping_add(1);
+            Some((id as u16, len))
+        }
+    }
 }
 
-pub struct Virtio<'a> {
-    blk_request_vq: &'a mut VirtioVirtq,
-    blk_req: &'a mut VirtioBlkReq,
-    blk_req_paddr: u32,
-    blk_capacity: u64,
+// virtio-mmio のトランスポート。version 1 (レガシー) と 2 (モダン) の両方に対応する
+pub struct VirtioMmio {
+    base: usize,
+    version: u32,
 }
 
-impl<'a> Virtio<'a> {
-    pub const SECTOR_SIZE: u64 = 512;
+impl VirtioMmio {
+    // スロットにデバイスがつながっていればそのデバイス ID を返す
+    pub fn probe(base: usize) -> Option<u32> {
+        let mmio = Self { base, version: 0 };
+        if mmio.read32(VIRTIO_REG_MAGIC) != VIRTIO_MAGIC {
+            return None;
+        }
+        match mmio.read32(VIRTIO_REG_DEVICE_ID) {
+            0 => None,
+            device_id => Some(device_id),
+        }
+    }
+
+    pub fn new(base: usize, device_id: u32) -> Self {
+        let mmio = Self { base, version: 0 };
+        if mmio.read32(VIRTIO_REG_MAGIC) != VIRTIO_MAGIC {
+            panic!("virtio: invalid magic value");
+        }
+        let version = mmio.read32(VIRTIO_REG_VERSION);
+        if version != 1 && version != 2 {
+            panic!("virtio: invalid version {version}");
+        }
+        if mmio.read32(VIRTIO_REG_DEVICE_ID) != device_id {
+            panic!("virtio: invalid device id");
+        }
+        Self { base, version }
+    }
 
-    pub fn new() -> Self {
-        unsafe {
-            if virtio_reg_read32(VIRTIO_REG_MAGIC) != 0x74726976 {
-                panic!("virtio: invalid magic value");
-            }
-            if virtio_reg_read32(VIRTIO_REG_VERSION) != 1 {
-                panic!("virtio: invalid version");
-            }
-            if virtio_reg_read32(VIRTIO_REG_DEVICE_ID) != VIRTIO_DEVICE_BLK {
-                panic!("virtio: invalid device id");
-            }
+    pub fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
 
-            virtio_reg_write32(VIRTIO_REG_DEVICE_STATUS, 0);
-            virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_ACK);
-            virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER);
-            virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_FEAT_OK);
-            let blk_request_vq = Self::virtq_init(0);
-            virtio_reg_write32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER_OK);
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
 
-            let blk_capacity = virtio_reg_read64(VIRTIO_REG_DEVICE_CONFIG) * Self::SECTOR_SIZE;
-            println!("virtio-blk: capacity is {} bytes\n", blk_capacity);
+    fn fetch_and_or32(&self, offset: usize, value: u32) {
+        let current_value = self.read32(offset);
+        self.write32(offset, current_value | value);
+    }
 
-            let blk_req_size = align_up(core::mem::size_of::<VirtioBlkReq>(), PAGE_SIZE);
-            let blk_req_paddr = alloc_pages(blk_req_size / PAGE_SIZE);
+    fn write64(&self, offset_low: usize, offset_high: usize, value: u64) {
+        self.write32(offset_low, value as u32);
+        self.write32(offset_high, (value >> 32) as u32);
+    }
 
-            Self {
-                blk_request_vq: blk_request_vq.as_mut().unwrap(),
-                blk_req: (blk_req_paddr as *mut VirtioBlkReq).as_mut().unwrap(),
-                blk_req_paddr,
-                blk_capacity,
+    fn read_features(&self) -> u64 {
+        self.write32(VIRTIO_REG_DEVICE_FEATURES_SEL, 0);
+        let low = self.read32(VIRTIO_REG_DEVICE_FEATURES) as u64;
+        if self.is_legacy() {
+            return low;
+        }
+        self.write32(VIRTIO_REG_DEVICE_FEATURES_SEL, 1);
+        let high = self.read32(VIRTIO_REG_DEVICE_FEATURES) as u64;
+        (high << 32) | low
+    }
+
+    fn write_features(&self, features: u64) {
+        self.write32(VIRTIO_REG_DRIVER_FEATURES_SEL, 0);
+        self.write32(VIRTIO_REG_DRIVER_FEATURES, features as u32);
+        if !self.is_legacy() {
+            self.write32(VIRTIO_REG_DRIVER_FEATURES_SEL, 1);
+            self.write32(VIRTIO_REG_DRIVER_FEATURES, (features >> 32) as u32);
+        }
+    }
+
+    // デバイスをリセットし、`wanted` のうちデバイスが提供する機能をネゴシエートする。
+    // モダンなデバイスでは VIRTIO_F_VERSION_1 も必要になる
+    pub fn init(&self, wanted: u64) -> u64 {
+        self.write32(VIRTIO_REG_DEVICE_STATUS, 0);
+        self.fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_ACK);
+        self.fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER);
+
+        let device_features = self.read_features();
+        let mut features = device_features & wanted;
+        if !self.is_legacy() {
+            if device_features & VIRTIO_F_VERSION_1 == 0 {
+                panic!("virtio: modern device does not offer VIRTIO_F_VERSION_1");
             }
+            features |= VIRTIO_F_VERSION_1;
+        }
+        self.write_features(features);
+
+        self.fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_FEAT_OK);
+        if !self.is_legacy() && self.read32(VIRTIO_REG_DEVICE_STATUS) & VIRTIO_STATUS_FEAT_OK == 0 {
+            panic!("virtio: device did not accept features {features:x}");
+        }
+
+        if self.is_legacy() {
+            self.write32(VIRTIO_REG_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
         }
+        features
     }
 
-    unsafe fn virtq_init(index: u32) -> *mut VirtioVirtq {
-        let virtq_size = align_up(core::mem::size_of::<VirtioVirtq>(), PAGE_SIZE);
+    pub fn driver_ok(&self) {
+        self.fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER_OK);
+    }
+
+    // ディスクリプタテーブルと avail リングの後ろに、ページ境界に揃えて used リングを置く。
+    // レガシーではこのレイアウトが必須で、モダンでもそのまま使える
+    pub unsafe fn virtq_init(&self, index: u32) -> VirtioVirtq {
+        self.write32(VIRTIO_REG_QUEUE_SEL, index);
+        if !self.is_legacy() && self.read32(VIRTIO_REG_QUEUE_READY) != 0 {
+            panic!("virtio: queue {index} is already in use");
+        }
+        let num_max = self.read32(VIRTIO_REG_QUEUE_NUM_MAX) as usize;
+        if num_max == 0 {
+            panic!("virtio: queue {index} is not available");
+        }
+        let num = core::cmp::min(num_max, VIRTQ_ENTRY_NUM);
+
+        let descs_size = mem::size_of::<VirtqDesc>() * num;
+        let avail_size = mem::size_of::<VirtqAvail>() + mem::size_of::<u16>() * (num + 1);
+        let used_offset = align_up(descs_size + avail_size, PAGE_SIZE);
+        let used_size = mem::size_of::<VirtqUsed>()
+            + mem::size_of::<VirtqUsedElem>() * num
+            + mem::size_of::<u16>();
+        let virtq_size = used_offset + align_up(used_size, PAGE_SIZE);
         let virtq_paddr = alloc_pages(virtq_size / PAGE_SIZE);
-        let vq = (virtq_paddr as *mut VirtioVirtq).as_mut().unwrap();
 
-        vq.queue_index = index;
-        let used_index = (&mut (vq.used) as *const VirtqUsed as *const u8)
-            .offset(core::mem::offset_of!(VirtqUsed, index) as isize);
-        vq.used_index = used_index as *mut u16;
+        let mut vq = VirtioVirtq {
+            queue_index: index,
+            num,
+            descs: virtq_paddr as *mut VirtqDesc,
+            avail: (virtq_paddr as usize + descs_size) as *mut VirtqAvail,
+            used: (virtq_paddr as usize + used_offset) as *mut VirtqUsed,
+            free_head: 0,
+            num_free: num,
+            last_used_index: 0,
+        };
+        for i in 0..num {
+            vq.desc(i as u16).next = (i + 1) as u16;
+        }
 
-        virtio_reg_write32(VIRTIO_REG_QUEUE_SEL, index);
-        virtio_reg_write32(VIRTIO_REG_QUEUE_NUM, VIRTQ_ENTRY_NUM as u32);
-        virtio_reg_write32(VIRTIO_REG_QUEUE_ALIGN, 0);
-        virtio_reg_write32(VIRTIO_REG_QUEUE_PFN, virtq_paddr);
+        self.write32(VIRTIO_REG_QUEUE_NUM, num as u32);
+        if self.is_legacy() {
+            self.write32(VIRTIO_REG_QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write32(VIRTIO_REG_QUEUE_PFN, virtq_paddr / PAGE_SIZE as u32);
+        } else {
+            self.write64(
+                VIRTIO_REG_QUEUE_DESC_LOW,
+                VIRTIO_REG_QUEUE_DESC_HIGH,
+                vq.descs as u64,
+            );
+            self.write64(
+                VIRTIO_REG_QUEUE_DRIVER_LOW,
+                VIRTIO_REG_QUEUE_DRIVER_HIGH,
+                vq.avail as u64,
+            );
+            self.write64(
+                VIRTIO_REG_QUEUE_DEVICE_LOW,
+                VIRTIO_REG_QUEUE_DEVICE_HIGH,
+                vq.used as u64,
+            );
+            self.write32(VIRTIO_REG_QUEUE_READY, 1);
+        }
 
         vq
     }
 
-    fn virtq_kick(vq: &mut VirtioVirtq, desc_index: u32) {
-        vq.avail.ring[vq.avail.index as usize % VIRTQ_ENTRY_NUM] = desc_index as u16;
-        vq.avail.index += 1;
-        unsafe { asm!("fence") }
-        virtio_reg_write32(VIRTIO_REG_QUEUE_NOTIFY as usize, vq.queue_index);
-        vq.last_used_index += 1;
+    pub fn virtq_kick(&self, vq: &mut VirtioVirtq, head: u16) {
+        vq.push_avail(head);
+        self.write32(VIRTIO_REG_QUEUE_NOTIFY, vq.queue_index);
+    }
+
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read32(VIRTIO_REG_INTERRUPT_STATUS);
+        self.write32(VIRTIO_REG_INTERRUPT_ACK, status);
+        status
     }
 
-    fn virtq_is_busy(vq: &mut VirtioVirtq) -> bool {
-        unsafe { vq.last_used_index != ptr::read_volatile(vq.used_index) }
+    // モダンなデバイスでは読み取り中に設定が変わっていないかを世代番号で確認する
+    pub fn config_generation(&self) -> u32 {
+        if self.is_legacy() {
+            0
+        } else {
+            self.read32(VIRTIO_REG_CONFIG_GENERATION)
+        }
+    }
+
+    pub fn read_config8(&self, offset: usize) -> u8 {
+        unsafe { read_volatile((self.base + VIRTIO_REG_DEVICE_CONFIG + offset) as *const u8) }
     }
 
-    pub fn read_write_disk(&mut self, buf: &mut [u8], sector: u64, is_write: bool) {
+    pub fn write_config8(&self, offset: usize, value: u8) {
         unsafe {
-            if sector >= self.blk_capacity / Self::SECTOR_SIZE as u64 {
-                println!(
-                    "virtio: tried to read/write sector={}, but capacity is {}",
-                    sector,
-                    self.blk_capacity / Self::SECTOR_SIZE as u64
-                );
-                return;
-            }
+            write_volatile(
+                (self.base + VIRTIO_REG_DEVICE_CONFIG + offset) as *mut u8,
+                value,
+            )
+        }
+    }
 
-            self.blk_req.sector = sector;
-            self.blk_req.type_ = if is_write {
-                VIRTIO_BLK_T_OUT
-            } else {
-                VIRTIO_BLK_T_IN
-            };
-            if is_write {
-                ptr::copy(
-                    buf as *mut _ as *mut u8,
-                    &mut self.blk_req.data as *mut [u8] as *mut u8,
-                    Self::SECTOR_SIZE as usize,
-                );
-            }
+    pub fn read_config32(&self, offset: usize) -> u32 {
+        self.read32(VIRTIO_REG_DEVICE_CONFIG + offset)
+    }
 
-            self.blk_request_vq.descs[0].addr = self.blk_req_paddr as u64;
-            self.blk_request_vq.descs[0].len =
-                (mem::size_of::<u32>() * 2 + mem::size_of::<u64>()) as u32;
-            self.blk_request_vq.descs[0].flags = VIRTQ_DESC_F_NEXT as u16;
-            self.blk_request_vq.descs[0].next = 1;
-
-            self.blk_request_vq.descs[1].addr =
-                self.blk_req_paddr as u64 + mem::offset_of!(VirtioBlkReq, data) as u64;
-            self.blk_request_vq.descs[1].len = Self::SECTOR_SIZE as u32;
-            self.blk_request_vq.descs[1].flags =
-                (VIRTQ_DESC_F_NEXT | if is_write { 0 } else { VIRTQ_DESC_F_WRITE }) as u16;
-            self.blk_request_vq.descs[1].next = 2;
-
-            self.blk_request_vq.descs[2].addr =
-                self.blk_req_paddr as u64 + mem::offset_of!(VirtioBlkReq, status) as u64;
-            self.blk_request_vq.descs[2].len = mem::size_of::<u8>() as u32;
-            self.blk_request_vq.descs[2].flags = VIRTQ_DESC_F_WRITE as u16;
-
-            Self::virtq_kick(self.blk_request_vq, 0);
-
-            while Self::virtq_is_busy(self.blk_request_vq) {}
-
-            if self.blk_req.status != 0 {
-                println!(
-                    "virtio: warn: failed to read/write sector={} status={}",
-                    sector, self.blk_req.status,
-                );
-                return;
-            }
+    pub fn read_config64(&self, offset: usize) -> u64 {
+        let low = self.read_config32(offset) as u64;
+        let high = self.read_config32(offset + 4) as u64;
+        (high << 32) | low
+    }
+}
 
-            if !is_write {
-                ptr::copy(
-                    &self.blk_req.data as *const [u8] as *const u8,
-                    buf as *mut _ as *mut u8,
-                    Self::SECTOR_SIZE as usize,
-                );
-            }
+static mut VIRTIO_DEVICE_IDS: [u32; VIRTIO_MMIO_COUNT] = [0; VIRTIO_MMIO_COUNT];
+
+pub fn virtio_slot_paddr(slot: usize) -> usize {
+    unsafe { crate::PLATFORM.virtio[slot].base }
+}
+
+pub fn virtio_slot_irq(slot: usize) -> u32 {
+    unsafe { crate::PLATFORM.virtio[slot].irq }
+}
+
+// デバイスツリーにある virtio-mmio スロットをすべて調べて、デバイス ID を記録する
+pub fn virtio_probe() -> [u32; VIRTIO_MMIO_COUNT] {
+    unsafe {
+        for slot in 0..crate::PLATFORM.virtio_count {
+            VIRTIO_DEVICE_IDS[slot] = VirtioMmio::probe(virtio_slot_paddr(slot)).unwrap_or(0);
         }
+        VIRTIO_DEVICE_IDS
     }
 }
+
+// `irq` を上げたスロットとそのデバイス ID を返す。見つけた virtio-mmio の
+// スロットの割り込みでなければ None
+pub fn virtio_irq_device(irq: u32) -> Option<(usize, u32)> {
+    let slot = (0..unsafe { crate::PLATFORM.virtio_count }).find(|&s| virtio_slot_irq(s) == irq)?;
+    match unsafe { VIRTIO_DEVICE_IDS[slot] } {
+        0 => None,
+        device_id => Some((slot, device_id)),
+    }
+}
+
+// リクエストは割り込みハンドラで完了する。`cond` が成り立つまで `chan` で眠り、
+// ドライバの割り込みハンドラは完了のたびに `chan` を起こす
+pub fn virtio_wait_until(chan: usize, cond: impl Fn() -> bool) {
+    crate::process::wait_event(chan, cond);
+}
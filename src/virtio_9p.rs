@@ -0,0 +1,168 @@
+use common::{align_up, PAGE_SIZE};
+
+use crate::{
+    memory::alloc_pages,
+    plic::plic_enable,
+    println,
+    virtio::{
+        virtio_slot_irq, virtio_slot_paddr, virtio_wait_until, VirtioMmio, VirtioVirtq,
+        VIRTIO_DEVICE_9P, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE,
+    },
+    PM,
+};
+use core::ptr::{self, read_volatile};
+
+const VIRTIO_9P_F_MOUNT_TAG: u64 = 1 << 0;
+const VIRTIO_9P_FEATURES: u64 = VIRTIO_9P_F_MOUNT_TAG;
+const VIRTIO_9P_CONFIG_TAG_LEN: usize = 0;
+const VIRTIO_9P_CONFIG_TAG: usize = 2;
+const VIRTIO_9P_TAG_MAX: usize = 32;
+// 1 つのメッセージの最大サイズ (Tversion で msize として伝える)
+pub const VIRTIO_9P_MSIZE: usize = 8192;
+
+pub struct Virtio9p {
+    mmio: VirtioMmio,
+    vq: VirtioVirtq,
+    tag: [u8; VIRTIO_9P_TAG_MAX],
+    tag_len: usize,
+    // 要求は 1 つずつ順番に処理する
+    tx_buf: *mut u8,
+    rx_buf: *mut u8,
+    busy: bool,
+    head: u16,
+    done: bool,
+    rx_len: usize,
+}
+
+impl Virtio9p {
+    pub fn new(slot: usize) -> Self {
+        unsafe {
+            let mmio = VirtioMmio::new(virtio_slot_paddr(slot), VIRTIO_DEVICE_9P);
+            let features = mmio.init(VIRTIO_9P_FEATURES);
+            let vq = mmio.virtq_init(0);
+            mmio.driver_ok();
+
+            let mut tag = [0; VIRTIO_9P_TAG_MAX];
+            let mut tag_len = 0;
+            if features & VIRTIO_9P_F_MOUNT_TAG != 0 {
+                tag_len = (mmio.read_config8(VIRTIO_9P_CONFIG_TAG_LEN) as usize
+                    | (mmio.read_config8(VIRTIO_9P_CONFIG_TAG_LEN + 1) as usize) << 8)
+                    .min(VIRTIO_9P_TAG_MAX);
+                for (i, c) in tag[0..tag_len].iter_mut().enumerate() {
+                    *c = mmio.read_config8(VIRTIO_9P_CONFIG_TAG + i);
+                }
+            }
+
+            let buf_pages = align_up(VIRTIO_9P_MSIZE, PAGE_SIZE) / PAGE_SIZE;
+            let p9 = Self {
+                mmio,
+                vq,
+                tag,
+                tag_len,
+                tx_buf: alloc_pages(buf_pages) as *mut u8,
+                rx_buf: alloc_pages(buf_pages) as *mut u8,
+                busy: false,
+                head: 0,
+                done: false,
+                rx_len: 0,
+            };
+            println!(
+                "virtio-9p: slot {} tag={} ({})",
+                slot,
+                p9.tag(),
+                if p9.mmio.is_legacy() {
+                    "legacy"
+                } else {
+                    "modern"
+                },
+            );
+
+            plic_enable(virtio_slot_irq(slot));
+            p9
+        }
+    }
+
+    pub fn tag(&self) -> &str {
+        core::str::from_utf8(&self.tag[0..self.tag_len]).unwrap_or("?")
+    }
+
+    pub fn handle_interrupt(&mut self) {
+        self.mmio.ack_interrupt();
+
+        while let Some((head, len)) = self.vq.pop_used_len() {
+            if self.busy && head == self.head {
+                self.rx_len = len as usize;
+                self.done = true;
+            } else {
+                println!("virtio-9p: warn: unexpected completion desc={}", head);
+            }
+            self.vq.free_descs(head);
+        }
+        unsafe { PM.wakeup(self.chan()) };
+    }
+
+    // 応答や、前の要求が終わるのを待つプロセスが眠るチャネル
+    fn chan(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    // T メッセージ `tx` を送り、返ってきた R メッセージを `rx` に書き込んでその長さを返す
+    pub fn request(&mut self, tx: &[u8], rx: &mut [u8]) -> usize {
+        assert!(tx.len() <= VIRTIO_9P_MSIZE);
+
+        let busy = ptr::addr_of!(self.busy);
+        virtio_wait_until(self.chan(), || unsafe { !read_volatile(busy) });
+        self.busy = true;
+        self.done = false;
+
+        unsafe { ptr::copy(tx.as_ptr(), self.tx_buf, tx.len()) };
+        let head = self.vq.alloc_descs(2).unwrap();
+        let desc = self.vq.desc(head);
+        desc.addr = self.tx_buf as u64;
+        desc.len = tx.len() as u32;
+        desc.flags = VIRTQ_DESC_F_NEXT as u16;
+        let next = desc.next;
+        let desc = self.vq.desc(next);
+        desc.addr = self.rx_buf as u64;
+        desc.len = VIRTIO_9P_MSIZE as u32;
+        desc.flags = VIRTQ_DESC_F_WRITE as u16;
+        self.head = head;
+        self.mmio.virtq_kick(&mut self.vq, head);
+
+        let done = ptr::addr_of!(self.done);
+        virtio_wait_until(self.chan(), || unsafe { read_volatile(done) });
+
+        let len = self.rx_len.min(rx.len());
+        unsafe { ptr::copy(self.rx_buf, rx.as_mut_ptr(), len) };
+        self.busy = false;
+        // 次の要求を出そうと待っているプロセスを起こす
+        unsafe { PM.wakeup(self.chan()) };
+        len
+    }
+}
+
+static mut VIRTIO_9P: Option<Virtio9p> = None;
+static mut VIRTIO_9P_SLOT: usize = 0;
+
+pub fn virtio_9p_init(slot: usize) {
+    unsafe {
+        if VIRTIO_9P.is_some() {
+            println!("virtio-9p: slot {slot}: only one 9p device is supported");
+            return;
+        }
+        let p9 = Virtio9p::new(slot);
+        VIRTIO_9P_SLOT = slot;
+        VIRTIO_9P = Some(p9);
+    }
+}
+
+pub fn virtio_9p() -> Option<&'static mut Virtio9p> {
+    unsafe { VIRTIO_9P.as_mut() }
+}
+
+pub fn virtio_9p_handle_interrupt(slot: usize) {
+    match virtio_9p() {
+        Some(p9) if unsafe { VIRTIO_9P_SLOT } == slot => p9.handle_interrupt(),
+        _ => {}
+    }
+}
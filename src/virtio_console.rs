@@ -0,0 +1,303 @@
+use common::PAGE_SIZE;
+
+use crate::{
+    memory::alloc_pages,
+    plic::plic_enable,
+    println,
+    virtio::{
+        virtio_slot_irq, virtio_slot_paddr, VirtioMmio, VirtioVirtq, VIRTIO_DEVICE_CONSOLE,
+        VIRTQ_DESC_F_WRITE,
+    },
+};
+use core::{mem, ptr};
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_FEATURES: u64 = VIRTIO_CONSOLE_F_MULTIPORT;
+const VIRTIO_CONSOLE_CONFIG_MAX_NR_PORTS: usize = 4;
+
+// マルチポートのコントロールメッセージ
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+
+// ポート 0 はコンソール、ポート 1 (あれば) はログの出力先として使う
+const VIRTIO_CONSOLE_PORT_LOG: u32 = 1;
+
+const VIRTIO_CONSOLE_RX_NUM: usize = 8;
+const VIRTIO_CONSOLE_RX_SIZE: usize = 256;
+const VIRTIO_CONSOLE_CTRL_RX_OFFSET: usize = VIRTIO_CONSOLE_RX_NUM * VIRTIO_CONSOLE_RX_SIZE;
+const VIRTIO_CONSOLE_INPUT_SIZE: usize = 256;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct VirtioConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+// ポート p の receiveq は 2 + 2p 番 (ポート 0 だけは 0 番)、transmitq はその次
+const fn port_rx_queue(port: u32) -> u32 {
+    if port == 0 {
+        0
+    } else {
+        2 + 2 * port
+    }
+}
+
+pub struct VirtioConsole {
+    mmio: VirtioMmio,
+    rx_vq: VirtioVirtq,
+    tx_vq: VirtioVirtq,
+    ctrl_rx_vq: Option<VirtioVirtq>,
+    ctrl_tx_vq: Option<VirtioVirtq>,
+    log_tx_vq: Option<VirtioVirtq>,
+    log_open: bool,
+    // 受信用のバッファ (ポート 0 とコントロール用) と送信用のバッファ。
+    // 送信は完了を待ってから戻るので 1 ページで足りる
+    rx_bufs: *mut u8,
+    tx_buf: *mut u8,
+    input: [u8; VIRTIO_CONSOLE_INPUT_SIZE],
+    input_read: usize,
+    input_write: usize,
+}
+
+impl VirtioConsole {
+    pub fn new(slot: usize) -> Self {
+        unsafe {
+            let mmio = VirtioMmio::new(virtio_slot_paddr(slot), VIRTIO_DEVICE_CONSOLE);
+            let features = mmio.init(VIRTIO_CONSOLE_FEATURES);
+            let multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+            let max_nr_ports = if multiport {
+                mmio.read_config32(VIRTIO_CONSOLE_CONFIG_MAX_NR_PORTS)
+            } else {
+                1
+            };
+
+            let rx_vq = mmio.virtq_init(0);
+            let tx_vq = mmio.virtq_init(1);
+            let (ctrl_rx_vq, ctrl_tx_vq) = if multiport {
+                (Some(mmio.virtq_init(2)), Some(mmio.virtq_init(3)))
+            } else {
+                (None, None)
+            };
+            let log_tx_vq = if max_nr_ports > VIRTIO_CONSOLE_PORT_LOG {
+                Some(mmio.virtq_init(port_rx_queue(VIRTIO_CONSOLE_PORT_LOG) + 1))
+            } else {
+                None
+            };
+            mmio.driver_ok();
+
+            println!(
+                "virtio-console: slot {} ({}, ports={})",
+                slot,
+                if mmio.is_legacy() { "legacy" } else { "modern" },
+                max_nr_ports,
+            );
+
+            let mut console = Self {
+                mmio,
+                rx_vq,
+                tx_vq,
+                ctrl_rx_vq,
+                ctrl_tx_vq,
+                log_tx_vq,
+                log_open: false,
+                rx_bufs: alloc_pages(1) as *mut u8,
+                tx_buf: alloc_pages(1) as *mut u8,
+                input: [0; VIRTIO_CONSOLE_INPUT_SIZE],
+                input_read: 0,
+                input_write: 0,
+            };
+
+            for i in 0..VIRTIO_CONSOLE_RX_NUM {
+                let buf = console.rx_bufs.add(i * VIRTIO_CONSOLE_RX_SIZE);
+                Self::post_rx(
+                    &console.mmio,
+                    &mut console.rx_vq,
+                    buf,
+                    VIRTIO_CONSOLE_RX_SIZE,
+                );
+            }
+            if let Some(vq) = console.ctrl_rx_vq.as_mut() {
+                for i in 0..VIRTIO_CONSOLE_RX_NUM {
+                    let buf = console
+                        .rx_bufs
+                        .add(VIRTIO_CONSOLE_CTRL_RX_OFFSET)
+                        .add(i * mem::size_of::<VirtioConsoleControl>());
+                    Self::post_rx(
+                        &console.mmio,
+                        vq,
+                        buf,
+                        mem::size_of::<VirtioConsoleControl>(),
+                    );
+                }
+                console.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+            }
+
+            // DEVICE_ADD などはすぐに届くので、ここで処理しておく
+            console.poll();
+            plic_enable(virtio_slot_irq(slot));
+            console
+        }
+    }
+
+    fn post_rx(mmio: &VirtioMmio, vq: &mut VirtioVirtq, buf: *mut u8, len: usize) {
+        let head = vq.alloc_descs(1).unwrap();
+        let desc = vq.desc(head);
+        desc.addr = buf as u64;
+        desc.len = len as u32;
+        desc.flags = VIRTQ_DESC_F_WRITE as u16;
+        mmio.virtq_kick(vq, head);
+    }
+
+    pub fn handle_interrupt(&mut self) {
+        self.mmio.ack_interrupt();
+        self.poll();
+    }
+
+    // 割り込みが処理されない間 (カーネル内でのポーリングなど) にも呼ばれる。
+    // 受信バッファは中身を取り出したら、同じディスクリプタのままデバイスに戻す
+    pub fn poll(&mut self) {
+        while let Some((head, len)) = self.rx_vq.pop_used_len() {
+            let buf = self.rx_vq.desc(head).addr as *const u8;
+            for i in 0..len as usize {
+                let ch = unsafe { *buf.add(i) };
+                if self.input_write - self.input_read < VIRTIO_CONSOLE_INPUT_SIZE {
+                    self.input[self.input_write % VIRTIO_CONSOLE_INPUT_SIZE] = ch;
+                    self.input_write += 1;
+                }
+            }
+            self.mmio.virtq_kick(&mut self.rx_vq, head);
+        }
+
+        // 返信すると次のコントロールメッセージが届くので、なくなるまで繰り返す
+        loop {
+            let mut events = [None; VIRTIO_CONSOLE_RX_NUM];
+            if let Some(vq) = self.ctrl_rx_vq.as_mut() {
+                for event in events.iter_mut() {
+                    let Some((head, _)) = vq.pop_used_len() else {
+                        break;
+                    };
+                    let msg = vq.desc(head).addr as *const VirtioConsoleControl;
+                    *event = Some(unsafe { ptr::read_unaligned(msg) });
+                    self.mmio.virtq_kick(vq, head);
+                }
+            }
+            if events[0].is_none() {
+                break;
+            }
+            for msg in events.iter().flatten() {
+                self.handle_control(msg);
+            }
+        }
+    }
+
+    fn handle_control(&mut self, msg: &VirtioConsoleControl) {
+        let id = msg.id;
+        match msg.event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                self.send_control(id, VIRTIO_CONSOLE_PORT_READY, 1);
+                if id == VIRTIO_CONSOLE_PORT_LOG && self.log_tx_vq.is_some() {
+                    self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+                }
+            }
+            VIRTIO_CONSOLE_CONSOLE_PORT => self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1),
+            VIRTIO_CONSOLE_PORT_OPEN if id == VIRTIO_CONSOLE_PORT_LOG => {
+                self.log_open = msg.value != 0
+            }
+            _ => {}
+        }
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16) {
+        let msg = VirtioConsoleControl { id, event, value };
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                ptr::addr_of!(msg) as *const u8,
+                mem::size_of::<VirtioConsoleControl>(),
+            )
+        };
+        if let Some(vq) = self.ctrl_tx_vq.as_mut() {
+            Self::transmit(&self.mmio, vq, self.tx_buf, bytes);
+        }
+    }
+
+    // 送信はデバイスがすぐに処理するので、used リングをポーリングして完了を待つ
+    fn transmit(mmio: &VirtioMmio, vq: &mut VirtioVirtq, tx_buf: *mut u8, bytes: &[u8]) {
+        for chunk in bytes.chunks(PAGE_SIZE) {
+            unsafe { ptr::copy(chunk.as_ptr(), tx_buf, chunk.len()) };
+            let head = vq.alloc_descs(1).unwrap();
+            let desc = vq.desc(head);
+            desc.addr = tx_buf as u64;
+            desc.len = chunk.len() as u32;
+            desc.flags = 0;
+            mmio.virtq_kick(vq, head);
+
+            loop {
+                match vq.pop_used() {
+                    Some(used) => {
+                        vq.free_descs(used);
+                        if used == head {
+                            break;
+                        }
+                    }
+                    None => core::hint::spin_loop(),
+                }
+            }
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        Self::transmit(&self.mmio, &mut self.tx_vq, self.tx_buf, bytes);
+    }
+
+    // ログ用のポートが開いていなければ false を返す
+    pub fn write_log(&mut self, bytes: &[u8]) -> bool {
+        match self.log_tx_vq.as_mut() {
+            Some(vq) if self.log_open => {
+                Self::transmit(&self.mmio, vq, self.tx_buf, bytes);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn getchar(&mut self) -> Option<u8> {
+        self.poll();
+        if self.input_read == self.input_write {
+            return None;
+        }
+        let ch = self.input[self.input_read % VIRTIO_CONSOLE_INPUT_SIZE];
+        self.input_read += 1;
+        Some(ch)
+    }
+}
+
+static mut VIRTIO_CONSOLE: Option<VirtioConsole> = None;
+static mut VIRTIO_CONSOLE_SLOT: usize = 0;
+
+pub fn virtio_console_init(slot: usize) {
+    unsafe {
+        if VIRTIO_CONSOLE.is_some() {
+            println!("virtio-console: slot {slot}: only one console is supported");
+            return;
+        }
+        let console = VirtioConsole::new(slot);
+        VIRTIO_CONSOLE_SLOT = slot;
+        VIRTIO_CONSOLE = Some(console);
+    }
+}
+
+pub fn virtio_console() -> Option<&'static mut VirtioConsole> {
+    unsafe { VIRTIO_CONSOLE.as_mut() }
+}
+
+pub fn virtio_console_handle_interrupt(slot: usize) {
+    match virtio_console() {
+        Some(console) if unsafe { VIRTIO_CONSOLE_SLOT } == slot => console.handle_interrupt(),
+        _ => {}
+    }
+}
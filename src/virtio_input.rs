@@ -0,0 +1,269 @@
+use common::{
+    KeyEvent, KEY_MOD_ALT, KEY_MOD_CAPSLOCK, KEY_MOD_CTRL, KEY_MOD_SHIFT, KEY_PRESSED,
+    KEY_RELEASED, PAGE_SIZE,
+};
+
+use crate::{
+    memory::alloc_pages,
+    plic::plic_enable,
+    println,
+    virtio::{
+        virtio_slot_irq, virtio_slot_paddr, VirtioMmio, VirtioVirtq, VIRTIO_DEVICE_INPUT,
+        VIRTQ_DESC_F_WRITE,
+    },
+    PM,
+};
+use core::{mem, ptr};
+
+const VIRTIO_INPUT_CONFIG_SELECT: usize = 0;
+const VIRTIO_INPUT_CONFIG_SUBSEL: usize = 1;
+const VIRTIO_INPUT_CONFIG_SIZE: usize = 2;
+const VIRTIO_INPUT_CONFIG_DATA: usize = 8;
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_NAME_MAX: usize = 32;
+
+const VIRTIO_INPUT_EVENT_NUM: usize = 64;
+const VIRTIO_INPUT_QUEUE_SIZE: usize = 64;
+
+// evdev のイベントの種類とキーコード
+const EV_KEY: u16 = 0x01;
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_LEFTALT: u16 = 56;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_RIGHTALT: u16 = 100;
+
+// US 配列のキーマップ。キーコード順に、シフトなしとシフトありの文字を並べる
+const KEYMAP: [&[u8; 2]; 58] = [
+    b"\0\0",
+    b"\x1b\x1b",
+    b"1!",
+    b"2@",
+    b"3#",
+    b"4$",
+    b"5%",
+    b"6^",
+    b"7&",
+    b"8*",
+    b"9(",
+    b"0)",
+    b"-_",
+    b"=+",
+    b"\x08\x08",
+    b"\t\t",
+    b"qQ",
+    b"wW",
+    b"eE",
+    b"rR",
+    b"tT",
+    b"yY",
+    b"uU",
+    b"iI",
+    b"oO",
+    b"pP",
+    b"[{",
+    b"]}",
+    b"\r\r",
+    b"\0\0",
+    b"aA",
+    b"sS",
+    b"dD",
+    b"fF",
+    b"gG",
+    b"hH",
+    b"jJ",
+    b"kK",
+    b"lL",
+    b";:",
+    b"'\"",
+    b"`~",
+    b"\0\0",
+    b"\\|",
+    b"zZ",
+    b"xX",
+    b"cC",
+    b"vV",
+    b"bB",
+    b"nN",
+    b"mM",
+    b",<",
+    b".>",
+    b"/?",
+    b"\0\0",
+    b"**",
+    b"\0\0",
+    b"  ",
+];
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct VirtioInputEvent {
+    type_: u16,
+    code: u16,
+    value: u32,
+}
+
+pub struct VirtioInput {
+    mmio: VirtioMmio,
+    event_vq: VirtioVirtq,
+    modifiers: u8,
+    queue: [KeyEvent; VIRTIO_INPUT_QUEUE_SIZE],
+    queue_read: usize,
+    queue_write: usize,
+}
+
+impl VirtioInput {
+    pub fn new(slot: usize) -> Self {
+        unsafe {
+            let mmio = VirtioMmio::new(virtio_slot_paddr(slot), VIRTIO_DEVICE_INPUT);
+            mmio.init(0);
+            let event_vq = mmio.virtq_init(0);
+            mmio.driver_ok();
+
+            let mut name = [0u8; VIRTIO_INPUT_NAME_MAX];
+            mmio.write_config8(VIRTIO_INPUT_CONFIG_SELECT, VIRTIO_INPUT_CFG_ID_NAME);
+            mmio.write_config8(VIRTIO_INPUT_CONFIG_SUBSEL, 0);
+            let name_len = (mmio.read_config8(VIRTIO_INPUT_CONFIG_SIZE) as usize).min(name.len());
+            for (i, c) in name[0..name_len].iter_mut().enumerate() {
+                *c = mmio.read_config8(VIRTIO_INPUT_CONFIG_DATA + i);
+            }
+            println!(
+                "virtio-input: slot {} {} ({})",
+                slot,
+                core::str::from_utf8(&name[0..name_len]).unwrap_or("?"),
+                if mmio.is_legacy() { "legacy" } else { "modern" },
+            );
+
+            let mut input = Self {
+                mmio,
+                event_vq,
+                modifiers: 0,
+                queue: [KeyEvent::default(); VIRTIO_INPUT_QUEUE_SIZE],
+                queue_read: 0,
+                queue_write: 0,
+            };
+
+            // イベントを受け取るバッファを 1 ページに並べてデバイスに渡しておく
+            let bufs = alloc_pages(1) as *mut VirtioInputEvent;
+            assert!(VIRTIO_INPUT_EVENT_NUM * mem::size_of::<VirtioInputEvent>() <= PAGE_SIZE);
+            for i in 0..VIRTIO_INPUT_EVENT_NUM {
+                let head = input.event_vq.alloc_descs(1).unwrap();
+                let desc = input.event_vq.desc(head);
+                desc.addr = bufs.add(i) as u64;
+                desc.len = mem::size_of::<VirtioInputEvent>() as u32;
+                desc.flags = VIRTQ_DESC_F_WRITE as u16;
+                input.mmio.virtq_kick(&mut input.event_vq, head);
+            }
+
+            plic_enable(virtio_slot_irq(slot));
+            input
+        }
+    }
+
+    pub fn handle_interrupt(&mut self) {
+        self.mmio.ack_interrupt();
+
+        while let Some(head) = self.event_vq.pop_used() {
+            let event = self.event_vq.desc(head).addr as *const VirtioInputEvent;
+            let event = unsafe { ptr::read_volatile(event) };
+            self.mmio.virtq_kick(&mut self.event_vq, head);
+            if event.type_ == EV_KEY {
+                self.key_event(event.code, event.value);
+            }
+        }
+        unsafe { PM.wakeup(self.chan()) };
+    }
+
+    // キー入力を待つプロセスが眠るチャネル
+    pub fn chan(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn key_event(&mut self, code: u16, value: u32) {
+        let value = value.min(2) as u8;
+        let modifier = match code {
+            KEY_LEFTSHIFT | KEY_RIGHTSHIFT => KEY_MOD_SHIFT,
+            KEY_LEFTCTRL | KEY_RIGHTCTRL => KEY_MOD_CTRL,
+            KEY_LEFTALT | KEY_RIGHTALT => KEY_MOD_ALT,
+            _ => 0,
+        };
+        match value {
+            KEY_RELEASED => self.modifiers &= !modifier,
+            _ => self.modifiers |= modifier,
+        }
+        if code == KEY_CAPSLOCK && value == KEY_PRESSED {
+            self.modifiers ^= KEY_MOD_CAPSLOCK;
+        }
+
+        let event = KeyEvent {
+            code,
+            value,
+            modifiers: self.modifiers,
+            ch: self.translate(code),
+        };
+        // いっぱいなら古いイベントを捨てる
+        if self.queue_write - self.queue_read == VIRTIO_INPUT_QUEUE_SIZE {
+            self.queue_read += 1;
+        }
+        self.queue[self.queue_write % VIRTIO_INPUT_QUEUE_SIZE] = event;
+        self.queue_write += 1;
+    }
+
+    fn translate(&self, code: u16) -> u8 {
+        let Some(keys) = KEYMAP.get(code as usize) else {
+            return 0;
+        };
+        let mut shift = self.modifiers & KEY_MOD_SHIFT != 0;
+        // Caps Lock は英字にだけ効く
+        if self.modifiers & KEY_MOD_CAPSLOCK != 0 && keys[0].is_ascii_lowercase() {
+            shift = !shift;
+        }
+        let ch = keys[shift as usize];
+        if self.modifiers & KEY_MOD_CTRL != 0 && ch.is_ascii_alphabetic() {
+            // Ctrl-A は 0x01 のように制御文字にする
+            return ch.to_ascii_lowercase() - b'a' + 1;
+        }
+        ch
+    }
+
+    pub fn read_event(&mut self) -> Option<KeyEvent> {
+        if self.queue_read == self.queue_write {
+            return None;
+        }
+        let event = self.queue[self.queue_read % VIRTIO_INPUT_QUEUE_SIZE];
+        self.queue_read += 1;
+        Some(event)
+    }
+
+    pub fn has_event(&self) -> bool {
+        self.queue_read != self.queue_write
+    }
+}
+
+static mut VIRTIO_INPUT: Option<VirtioInput> = None;
+static mut VIRTIO_INPUT_SLOT: usize = 0;
+
+pub fn virtio_input_init(slot: usize) {
+    unsafe {
+        if VIRTIO_INPUT.is_some() {
+            println!("virtio-input: slot {slot}: only one input device is supported");
+            return;
+        }
+        let input = VirtioInput::new(slot);
+        VIRTIO_INPUT_SLOT = slot;
+        VIRTIO_INPUT = Some(input);
+    }
+}
+
+pub fn virtio_input() -> Option<&'static mut VirtioInput> {
+    unsafe { VIRTIO_INPUT.as_mut() }
+}
+
+pub fn virtio_input_handle_interrupt(slot: usize) {
+    match virtio_input() {
+        Some(input) if unsafe { VIRTIO_INPUT_SLOT } == slot => input.handle_interrupt(),
+        _ => {}
+    }
+}
@@ -0,0 +1,167 @@
+use common::{align_up, PAGE_SIZE};
+
+use crate::{
+    memory::alloc_pages,
+    plic::plic_enable,
+    println,
+    virtio::{
+        virtio_slot_irq, virtio_slot_paddr, VirtioMmio, VirtioVirtq, VIRTIO_DEVICE_NET,
+        VIRTQ_DESC_F_WRITE,
+    },
+};
+use core::ptr;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_FEATURES: u64 = VIRTIO_NET_F_MAC;
+const VIRTIO_NET_CONFIG_MAC: usize = 0;
+
+// レガシーでは num_buffers がないので 10 バイト、モダンでは常に 12 バイト
+const VIRTIO_NET_HDR_SIZE_LEGACY: usize = 10;
+const VIRTIO_NET_HDR_SIZE: usize = 12;
+const VIRTIO_NET_RX_NUM: usize = 16;
+// ヘッダとイーサネットフレーム (最大 1514 バイト) が入る大きさ
+const VIRTIO_NET_BUF_SIZE: usize = 2048;
+// MAC アドレスを持たないデバイス用のローカルなアドレス
+const VIRTIO_NET_DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+pub struct VirtioNet {
+    mmio: VirtioMmio,
+    rx_vq: VirtioVirtq,
+    tx_vq: VirtioVirtq,
+    hdr_size: usize,
+    rx_bufs: *mut u8,
+    tx_buf: *mut u8,
+    pub mac: [u8; 6],
+}
+
+impl VirtioNet {
+    pub fn new(slot: usize) -> Self {
+        unsafe {
+            let mmio = VirtioMmio::new(virtio_slot_paddr(slot), VIRTIO_DEVICE_NET);
+            let features = mmio.init(VIRTIO_NET_FEATURES);
+            let rx_vq = mmio.virtq_init(0);
+            let tx_vq = mmio.virtq_init(1);
+            mmio.driver_ok();
+
+            let mut mac = VIRTIO_NET_DEFAULT_MAC;
+            if features & VIRTIO_NET_F_MAC != 0 {
+                for (i, b) in mac.iter_mut().enumerate() {
+                    *b = mmio.read_config8(VIRTIO_NET_CONFIG_MAC + i);
+                }
+            }
+            println!(
+                "virtio-net: slot {} mac {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} ({})",
+                slot,
+                mac[0],
+                mac[1],
+                mac[2],
+                mac[3],
+                mac[4],
+                mac[5],
+                if mmio.is_legacy() { "legacy" } else { "modern" },
+            );
+
+            let rx_bufs_size = align_up(VIRTIO_NET_RX_NUM * VIRTIO_NET_BUF_SIZE, PAGE_SIZE);
+            let mut net = Self {
+                hdr_size: if mmio.is_legacy() {
+                    VIRTIO_NET_HDR_SIZE_LEGACY
+                } else {
+                    VIRTIO_NET_HDR_SIZE
+                },
+                mmio,
+                rx_vq,
+                tx_vq,
+                rx_bufs: alloc_pages(rx_bufs_size / PAGE_SIZE) as *mut u8,
+                tx_buf: alloc_pages(1) as *mut u8,
+                mac,
+            };
+
+            for i in 0..VIRTIO_NET_RX_NUM {
+                let head = net.rx_vq.alloc_descs(1).unwrap();
+                let desc = net.rx_vq.desc(head);
+                desc.addr = net.rx_bufs.add(i * VIRTIO_NET_BUF_SIZE) as u64;
+                desc.len = VIRTIO_NET_BUF_SIZE as u32;
+                desc.flags = VIRTQ_DESC_F_WRITE as u16;
+                net.mmio.virtq_kick(&mut net.rx_vq, head);
+            }
+
+            plic_enable(virtio_slot_irq(slot));
+            net
+        }
+    }
+
+    // 受信したフレームはプロトコルスタックが net_poll で取り出すので、ここでは確認応答だけ
+    pub fn handle_interrupt(&mut self) {
+        self.mmio.ack_interrupt();
+    }
+
+    // 受信したフレームを 1 つ (virtio-net のヘッダを除いて) `buf` にコピーし、
+    // 受信バッファをデバイスに返す
+    pub fn recv(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let (head, len) = self.rx_vq.pop_used_len()?;
+        let frame = self.rx_vq.desc(head).addr as *const u8;
+        let len = core::cmp::min((len as usize).saturating_sub(self.hdr_size), buf.len());
+        unsafe { ptr::copy(frame.add(self.hdr_size), buf.as_mut_ptr(), len) };
+        self.mmio.virtq_kick(&mut self.rx_vq, head);
+        Some(len)
+    }
+
+    // 送信はデバイスがすぐに処理するので、used リングをポーリングして完了を待つ
+    pub fn send(&mut self, frame: &[u8]) {
+        let len = self.hdr_size + frame.len();
+        if len > PAGE_SIZE {
+            println!("virtio-net: frame too large ({} bytes)", frame.len());
+            return;
+        }
+
+        unsafe {
+            // チェックサムのオフロードなどは使わないのでヘッダはすべて 0
+            ptr::write_bytes(self.tx_buf, 0, self.hdr_size);
+            ptr::copy(frame.as_ptr(), self.tx_buf.add(self.hdr_size), frame.len());
+        }
+        let head = self.tx_vq.alloc_descs(1).unwrap();
+        let desc = self.tx_vq.desc(head);
+        desc.addr = self.tx_buf as u64;
+        desc.len = len as u32;
+        desc.flags = 0;
+        self.mmio.virtq_kick(&mut self.tx_vq, head);
+
+        loop {
+            match self.tx_vq.pop_used() {
+                Some(used) => {
+                    self.tx_vq.free_descs(used);
+                    if used == head {
+                        break;
+                    }
+                }
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+}
+
+static mut VIRTIO_NET: Option<VirtioNet> = None;
+static mut VIRTIO_NET_SLOT: usize = 0;
+
+pub fn virtio_net_init(slot: usize) {
+    unsafe {
+        if VIRTIO_NET.is_some() {
+            println!("virtio-net: slot {slot}: only one network device is supported");
+            return;
+        }
+        let net = VirtioNet::new(slot);
+        VIRTIO_NET_SLOT = slot;
+        VIRTIO_NET = Some(net);
+    }
+}
+
+pub fn virtio_net() -> Option<&'static mut VirtioNet> {
+    unsafe { VIRTIO_NET.as_mut() }
+}
+
+pub fn virtio_net_handle_interrupt(slot: usize) {
+    match virtio_net() {
+        Some(net) if unsafe { VIRTIO_NET_SLOT } == slot => net.handle_interrupt(),
+        _ => {}
+    }
+}
@@ -0,0 +1,79 @@
+use crate::{
+    memory::alloc_pages,
+    println,
+    virtio::{virtio_slot_paddr, VirtioMmio, VirtioVirtq, VIRTIO_DEVICE_RNG, VIRTQ_DESC_F_WRITE},
+};
+use common::PAGE_SIZE;
+use core::ptr;
+
+pub struct VirtioRng {
+    mmio: VirtioMmio,
+    vq: VirtioVirtq,
+    buf: *mut u8,
+}
+
+impl VirtioRng {
+    pub fn new(slot: usize) -> Self {
+        unsafe {
+            let mmio = VirtioMmio::new(virtio_slot_paddr(slot), VIRTIO_DEVICE_RNG);
+            mmio.init(0);
+            let vq = mmio.virtq_init(0);
+            mmio.driver_ok();
+
+            println!(
+                "virtio-rng: slot {} ({})",
+                slot,
+                if mmio.is_legacy() { "legacy" } else { "modern" },
+            );
+
+            Self {
+                mmio,
+                vq,
+                buf: alloc_pages(1) as *mut u8,
+            }
+        }
+    }
+
+    // デバイスが書き込んだバイト数を返す。エントロピーが足りなければ要求より少ないこともある。
+    // 起動時や再シードのときにしか使わないので、割り込みは使わずに used リングをポーリングする
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = core::cmp::min(buf.len(), PAGE_SIZE);
+        let head = self.vq.alloc_descs(1).unwrap();
+        let desc = self.vq.desc(head);
+        desc.addr = self.buf as u64;
+        desc.len = len as u32;
+        desc.flags = VIRTQ_DESC_F_WRITE as u16;
+        self.mmio.virtq_kick(&mut self.vq, head);
+
+        let filled = loop {
+            match self.vq.pop_used_len() {
+                Some((used, filled)) => {
+                    self.vq.free_descs(used);
+                    if used == head {
+                        break filled as usize;
+                    }
+                }
+                None => core::hint::spin_loop(),
+            }
+        };
+        let filled = core::cmp::min(filled, len);
+        unsafe { ptr::copy(self.buf, buf.as_mut_ptr(), filled) };
+        filled
+    }
+}
+
+static mut VIRTIO_RNG: Option<VirtioRng> = None;
+
+pub fn virtio_rng_init(slot: usize) {
+    unsafe {
+        if VIRTIO_RNG.is_some() {
+            println!("virtio-rng: slot {slot}: only one entropy device is supported");
+            return;
+        }
+        VIRTIO_RNG = Some(VirtioRng::new(slot));
+    }
+}
+
+pub fn virtio_rng() -> Option<&'static mut VirtioRng> {
+    unsafe { VIRTIO_RNG.as_mut() }
+}
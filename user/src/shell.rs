@@ -1,56 +1,342 @@
-use crate::{exit, getchar, putchar, readfile, writefile};
+use common::{
+    KeyEvent, SockAddr, Termios, Timespec, CLOCK_MONOTONIC, CLOCK_REALTIME, ECHO, EINTR, EOF,
+    ICANON, KEY_PRESSED, KEY_RELEASED, SIGINT, SIG_DFL, SOCK_DGRAM, SOCK_STREAM,
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    accept, bind, clock_gettime, close, exit, getchar, getpid, getrandom, kill, listen, putchar,
+    readfile, readkey, reboot, recv, recvfrom, send, sendto, shutdown, signal, sleep, socket,
+    tcgetattr, tcsetattr, writefile,
+};
+
+// QEMU のユーザーモードネットワークでは 10.0.2.2 がホストになる
+const HOST_ADDR: SockAddr = SockAddr {
+    addr: [10, 0, 2, 2],
+    port: 5555,
+};
+const UDP_ECHO_PORT: u16 = 7777;
+const RSHD_PORT: u16 = 2323;
+
+// Ctrl-C が押されたら立つ。時間のかかるコマンドはこれを見て途中でやめる
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_sig: u32) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+// コマンドの出力先。rshd から実行されたときは接続先のソケットに書く
+#[derive(Copy, Clone)]
+enum Output {
+    Console,
+    Socket(u32),
+}
+
+impl Output {
+    fn print(self, s: &str) {
+        match self {
+            Output::Console => {
+                for c in s.bytes() {
+                    putchar(c);
+                }
+            }
+            Output::Socket(fd) => {
+                send(fd, s.as_bytes());
+            }
+        }
+    }
+}
 
 #[no_mangle]
 fn main() {
+    signal(SIGINT, on_sigint as usize);
     loop {
+        INTERRUPTED.store(false, Ordering::Relaxed);
         print("> ");
+        // 行の編集と表示はカーネルの TTY がするので、改行まで読むだけでよい
         let mut cmdline: [u8; 128] = [0; 128];
         let mut count = 0;
         loop {
-            let ch = getchar() as u8;
-            putchar(ch);
-            if ch == b'\r' {
-                cmdline[count] = b'\0';
+            let ch = getchar();
+            if ch == EOF {
                 print("\n");
-                break;
-            } else {
-                cmdline[count] = ch;
+                exit();
             }
-
-            count += 1;
-            if count == 128 {
+            if ch == EINTR {
+                continue;
+            }
+            if ch as u8 == b'\n' {
                 break;
             }
+            // 入りきらない分は捨てる
+            if count < cmdline.len() {
+                cmdline[count] = ch as u8;
+                count += 1;
+            }
         }
         match core::str::from_utf8(&cmdline[..count]) {
+            Ok("exit") => exit(),
+            Ok("shutdown") => shutdown(),
+            Ok("reboot") => reboot(),
+            Ok("rshd") => rshd(),
+            Ok("") => continue,
+            Ok("raw") => raw(),
+            Ok("spin") => spin(),
+            Ok("suicide") => {
+                // ハンドラを外して自分に SIGINT を送ると、既定の動作で終了する
+                signal(SIGINT, SIG_DFL as usize);
+                kill(getpid(), SIGINT);
+            }
+            Ok(s) => run(s, Output::Console),
+            Err(_) => print("command not found\n"),
+        }
+        print("\n");
+    }
+}
+
+fn run(s: &str, out: Output) {
+    if s == "hello" {
+        out.print("Hello world from shell!\n");
+    } else if s == "readfile" {
+        let mut buf: [u8; 128] = [0; 128];
+        readfile("./lorem.txt\0", &mut buf, 128);
+        match core::str::from_utf8(&buf) {
             Ok(s) => {
-                if s == "hello" {
-                    print("Hello world from shell!\n");
-                } else if s == "exit" {
-                    exit();
-                } else if s == "readfile" {
-                    let mut buf: [u8; 128] = [0; 128];
-                    readfile("./lorem.txt\0", &mut buf, 128);
-                    match core::str::from_utf8(&buf) {
-                        Ok(s) => {
-                            print(s);
-                        }
-                        Err(_) => print("error"),
-                    }
-                } else if s == "writefile" {
-                    writefile("./lorem.txt\0", b"Hello from virtio\n\0", 128);
-                } else {
-                    print("command not found\n");
+                out.print(s);
+            }
+            Err(_) => out.print("error"),
+        }
+    } else if s == "readhost" {
+        let mut buf: [u8; 128] = [0; 128];
+        let len = readfile("/host/hello.txt\0", &mut buf, 128);
+        match core::str::from_utf8(&buf[0..len.min(128) as usize]) {
+            Ok(s) if len != 0xffff_fffe => out.print(s),
+            _ => out.print("readhost: failed\n"),
+        }
+    } else if s == "writefile" {
+        writefile("./lorem.txt\0", b"Hello from virtio\n\0", 128);
+    } else if s == "random" {
+        let mut buf = [0u8; 16];
+        getrandom(&mut buf);
+        for b in buf {
+            const HEX: &[u8; 16] = b"0123456789abcdef";
+            let hex = [HEX[(b >> 4) as usize], HEX[(b & 0xf) as usize]];
+            out.print(core::str::from_utf8(&hex).unwrap());
+        }
+        out.print("\n");
+    } else if s == "keys" {
+        // Esc が押されるまでキーボードのイベントを表示する
+        let mut event = KeyEvent::default();
+        loop {
+            if readkey(&mut event, 0) != 1 {
+                out.print("keys: no keyboard\n");
+                break;
+            }
+            let kind = match event.value {
+                KEY_RELEASED => "release",
+                KEY_PRESSED => "press",
+                _ => "repeat",
+            };
+            out.print(kind);
+            out.print(" ");
+            print_hex(out, event.code as u32);
+            if event.ch.is_ascii_graphic() {
+                out.print(" '");
+                out.print(core::str::from_utf8(&[event.ch]).unwrap());
+                out.print("'");
+            }
+            out.print("\n");
+            if event.ch == 0x1b && event.value != KEY_RELEASED {
+                break;
+            }
+        }
+    } else if s == "date" {
+        let mut ts = Timespec::default();
+        clock_gettime(CLOCK_REALTIME, &mut ts);
+        print_date(out, ts.sec);
+        out.print(" UTC\n");
+    } else if s == "uptime" {
+        let mut ts = Timespec::default();
+        clock_gettime(CLOCK_MONOTONIC, &mut ts);
+        print_dec(out, ts.sec as u32);
+        out.print(".");
+        print_dec_width(out, ts.nsec / 1_000_000, 3);
+        out.print("s\n");
+    } else if s == "sleep" {
+        if sleep(1000) == EINTR {
+            out.print("sleep: interrupted\n");
+        }
+    } else if s == "udpsend" {
+        let fd = socket(SOCK_DGRAM);
+        if sendto(fd, b"Hello from shell!\n", &HOST_ADDR) == 0xffff_ffff {
+            out.print("udpsend: failed\n");
+        }
+        close(fd);
+    } else if s == "udpecho" {
+        let fd = socket(SOCK_DGRAM);
+        let addr = SockAddr {
+            addr: [0; 4],
+            port: UDP_ECHO_PORT,
+        };
+        bind(fd, &addr);
+        let mut buf: [u8; 128] = [0; 128];
+        let mut from = SockAddr::default();
+        let len = recvfrom(fd, &mut buf, &mut from);
+        if len != 0xffff_ffff {
+            sendto(fd, &buf[0..len as usize], &from);
+        }
+        close(fd);
+    } else {
+        out.print("command not found\n");
+    }
+}
+
+// TCP で 1 つの接続を受け付け、届いた行をコマンドとして実行する。
+// ホストからは `nc localhost 2323` などで接続できる
+fn rshd() {
+    let fd = socket(SOCK_STREAM);
+    let addr = SockAddr {
+        addr: [0; 4],
+        port: RSHD_PORT,
+    };
+    if bind(fd, &addr) == 0xffff_ffff || listen(fd, 1) == 0xffff_ffff {
+        print("rshd: failed to listen\n");
+        close(fd);
+        return;
+    }
+    print("rshd: waiting for a connection on port 2323\n");
+
+    let mut from = SockAddr::default();
+    let conn = accept(fd, &mut from);
+    close(fd);
+    if conn == 0xffff_ffff {
+        print("rshd: accept failed\n");
+        return;
+    }
+    print("rshd: connected\n");
+
+    let out = Output::Socket(conn);
+    let mut line: [u8; 128] = [0; 128];
+    let mut count = 0;
+    out.print("> ");
+    'session: loop {
+        let mut buf: [u8; 128] = [0; 128];
+        let len = recv(conn, &mut buf);
+        if len == 0 || len == 0xffff_ffff {
+            break;
+        }
+        for &ch in &buf[0..len as usize] {
+            if ch == b'\r' {
+                continue;
+            }
+            if ch != b'\n' {
+                if count < line.len() {
+                    line[count] = ch;
+                    count += 1;
                 }
+                continue;
             }
-            Err(_) => print("command not found\n"),
+
+            match core::str::from_utf8(&line[..count]) {
+                Ok("exit") => break 'session,
+                Ok("") => {}
+                Ok(s) => run(s, out),
+                Err(_) => out.print("command not found\n"),
+            }
+            count = 0;
+            out.print("> ");
         }
+    }
+    close(conn);
+    print("rshd: disconnected\n");
+}
+
+// Ctrl-C が押されるまで何もせずに回り続ける
+fn spin() {
+    print("spinning; press Ctrl-C to stop\n");
+    let mut count: u32 = 0;
+    while !INTERRUPTED.load(Ordering::Relaxed) {
+        count = count.wrapping_add(1);
+    }
+    print("spin: interrupted after ");
+    print_hex(Output::Console, count);
+    print(" iterations\n");
+}
+
+// TTY を raw モードにして、'q' が押されるまで届いたバイトをそのまま表示する
+fn raw() {
+    let mut saved = Termios::default();
+    tcgetattr(&mut saved);
+    let mut termios = saved;
+    termios.lflag &= !(ICANON | ECHO);
+    tcsetattr(&termios);
+
+    print("raw mode: press q to quit\n");
+    loop {
+        let ch = getchar();
+        if ch == EOF || ch == EINTR || ch as u8 == b'q' {
+            break;
+        }
+        print_hex(Output::Console, ch);
         print("\n");
     }
+    tcsetattr(&saved);
 }
 
-fn print(s: &str) {
-    for c in s.bytes() {
-        putchar(c);
+fn print_hex(out: Output, value: u32) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let mut buf = [0u8; 8];
+    for (i, c) in buf.iter_mut().enumerate() {
+        *c = HEX[((value >> (28 - 4 * i)) & 0xf) as usize];
+    }
+    out.print(core::str::from_utf8(&buf).unwrap());
+}
+
+fn print_dec(out: Output, value: u32) {
+    print_dec_width(out, value, 1);
+}
+
+// `width` 桁に満たなければ 0 で埋める
+fn print_dec_width(out: Output, mut value: u32, width: usize) {
+    let mut buf = [b'0'; 10];
+    let mut len = 0;
+    while value > 0 || len < width {
+        buf[buf.len() - 1 - len] = (value % 10) as u8 + b'0';
+        value /= 10;
+        len += 1;
     }
+    out.print(core::str::from_utf8(&buf[buf.len() - len..]).unwrap());
+}
+
+// 1970-01-01 からの秒を "YYYY-MM-DD hh:mm:ss" で表示する
+fn print_date(out: Output, secs: u64) {
+    let days = (secs / 86400) as i64;
+    let rem = (secs % 86400) as u32;
+
+    // 3 月始まりの 400 年周期で日付に直す (Howard Hinnant の civil_from_days)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    print_dec_width(out, year as u32, 4);
+    out.print("-");
+    print_dec_width(out, month as u32, 2);
+    out.print("-");
+    print_dec_width(out, day as u32, 2);
+    out.print(" ");
+    print_dec_width(out, rem / 3600, 2);
+    out.print(":");
+    print_dec_width(out, rem / 60 % 60, 2);
+    out.print(":");
+    print_dec_width(out, rem % 60, 2);
+}
+
+fn print(s: &str) {
+    Output::Console.print(s);
 }
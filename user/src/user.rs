@@ -1,10 +1,17 @@
 #![no_std]
 #![no_main]
 #![feature(naked_functions)]
+#![feature(asm_const)]
 
 mod shell;
 
-use common::{SYS_EXIT, SYS_GETCHAR, SYS_PUTCHAR, SYS_READFILE, SYS_WRITEFILE};
+use common::{
+    KeyEvent, SockAddr, SockMsg, Termios, Timespec, SYS_ACCEPT, SYS_BIND, SYS_CLOCK_GETTIME,
+    SYS_CLOSE, SYS_CONNECT, SYS_EXIT, SYS_GETCHAR, SYS_GETPID, SYS_GETRANDOM, SYS_KILL, SYS_LISTEN,
+    SYS_PUTCHAR, SYS_READFILE, SYS_READKEY, SYS_REBOOT, SYS_RECV, SYS_RECVFROM, SYS_SEND,
+    SYS_SENDTO, SYS_SHUTDOWN, SYS_SIGACTION, SYS_SIGRETURN, SYS_SLEEP, SYS_SOCKET, SYS_TCGETATTR,
+    SYS_TCSETATTR, SYS_WRITEFILE,
+};
 use core::{arch::asm, panic::PanicInfo};
 
 extern "C" {
@@ -32,6 +39,30 @@ fn exit() {
     loop {}
 }
 
+// ディスクを書き戻してから電源を切る。戻ってこない
+pub fn shutdown() {
+    unsafe { syscall(SYS_SHUTDOWN, 0, 0, 0) };
+    loop {}
+}
+
+pub fn reboot() {
+    unsafe { syscall(SYS_REBOOT, 0, 0, 0) };
+    loop {}
+}
+
+// シグナルハンドラの戻り先。ハンドラから戻ると sigreturn で割り込まれたところに戻る
+#[naked]
+extern "C" fn sigreturn_trampoline() {
+    unsafe {
+        asm!(
+            "li a3, {sysno}",
+            "ecall",
+            sysno = const SYS_SIGRETURN,
+            options(noreturn)
+        );
+    }
+}
+
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     loop {}
@@ -58,10 +89,50 @@ pub fn putchar(ch: u8) {
     }
 }
 
+// 入力の終わりでは EOF を返す
 pub fn getchar() -> u32 {
     unsafe { syscall(SYS_GETCHAR, 0, 0, 0) }
 }
 
+pub fn getpid() -> u32 {
+    unsafe { syscall(SYS_GETPID, 0, 0, 0) }
+}
+
+pub fn kill(pid: u32, sig: u32) -> u32 {
+    unsafe { syscall(SYS_KILL, pid, sig, 0) }
+}
+
+// `handler` は SIG_DFL, SIG_IGN か extern "C" fn(sig: u32) のアドレス。
+// それまでのハンドラを返す
+pub fn signal(sig: u32, handler: usize) -> u32 {
+    unsafe {
+        syscall(
+            SYS_SIGACTION,
+            sig,
+            handler as u32,
+            sigreturn_trampoline as usize as u32,
+        )
+    }
+}
+
+// `clock` は CLOCK_REALTIME か CLOCK_MONOTONIC
+pub fn clock_gettime(clock: u32, ts: &mut Timespec) -> u32 {
+    unsafe { syscall(SYS_CLOCK_GETTIME, clock, ts as *mut Timespec as u32, 0) }
+}
+
+// `ms` ミリ秒眠る。シグナルで起こされたら EINTR を返す
+pub fn sleep(ms: u32) -> u32 {
+    unsafe { syscall(SYS_SLEEP, ms, 0, 0) }
+}
+
+pub fn tcgetattr(termios: &mut Termios) -> u32 {
+    unsafe { syscall(SYS_TCGETATTR, termios as *mut Termios as u32, 0, 0) }
+}
+
+pub fn tcsetattr(termios: &Termios) -> u32 {
+    unsafe { syscall(SYS_TCSETATTR, termios as *const Termios as u32, 0, 0) }
+}
+
 pub fn readfile(filename: &str, buf: &mut [u8], len: u32) -> u32 {
     unsafe {
         syscall(
@@ -83,3 +154,65 @@ pub fn writefile(filename: &str, buf: &[u8], len: u32) -> u32 {
         )
     }
 }
+
+pub fn getrandom(buf: &mut [u8]) -> u32 {
+    unsafe { syscall(SYS_GETRANDOM, buf.as_mut_ptr() as u32, buf.len() as u32, 0) }
+}
+
+// イベントがあれば `event` に書き込んで 1 を返す。flags に READKEY_NONBLOCK を
+// 渡すと、イベントがないときは待たずに 0 を返す
+pub fn readkey(event: &mut KeyEvent, flags: u32) -> u32 {
+    unsafe { syscall(SYS_READKEY, event as *mut KeyEvent as u32, flags, 0) }
+}
+
+pub fn socket(type_: u32) -> u32 {
+    unsafe { syscall(SYS_SOCKET, type_, 0, 0) }
+}
+
+pub fn bind(fd: u32, addr: &SockAddr) -> u32 {
+    unsafe { syscall(SYS_BIND, fd, addr as *const SockAddr as u32, 0) }
+}
+
+pub fn sendto(fd: u32, buf: &[u8], addr: &SockAddr) -> u32 {
+    let msg = SockMsg {
+        buf: buf.as_ptr() as u32,
+        len: buf.len() as u32,
+        addr: *addr,
+    };
+    unsafe { syscall(SYS_SENDTO, fd, &msg as *const SockMsg as u32, 0) }
+}
+
+pub fn recvfrom(fd: u32, buf: &mut [u8], addr: &mut SockAddr) -> u32 {
+    let mut msg = SockMsg {
+        buf: buf.as_mut_ptr() as u32,
+        len: buf.len() as u32,
+        addr: SockAddr::default(),
+    };
+    let len = unsafe { syscall(SYS_RECVFROM, fd, &mut msg as *mut SockMsg as u32, 0) };
+    *addr = msg.addr;
+    len
+}
+
+pub fn listen(fd: u32, backlog: u32) -> u32 {
+    unsafe { syscall(SYS_LISTEN, fd, backlog, 0) }
+}
+
+pub fn accept(fd: u32, addr: &mut SockAddr) -> u32 {
+    unsafe { syscall(SYS_ACCEPT, fd, addr as *mut SockAddr as u32, 0) }
+}
+
+pub fn connect(fd: u32, addr: &SockAddr) -> u32 {
+    unsafe { syscall(SYS_CONNECT, fd, addr as *const SockAddr as u32, 0) }
+}
+
+pub fn send(fd: u32, buf: &[u8]) -> u32 {
+    unsafe { syscall(SYS_SEND, fd, buf.as_ptr() as u32, buf.len() as u32) }
+}
+
+pub fn recv(fd: u32, buf: &mut [u8]) -> u32 {
+    unsafe { syscall(SYS_RECV, fd, buf.as_mut_ptr() as u32, buf.len() as u32) }
+}
+
+pub fn close(fd: u32) -> u32 {
+    unsafe { syscall(SYS_CLOSE, fd, 0, 0) }
+}